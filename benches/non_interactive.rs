@@ -0,0 +1,36 @@
+//! Compares a compute loop on a VM that polls its devices on every read
+//! with the same loop on `VM::non_interactive`. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use lc3::{assemble, BufferIo, Diagnostics, VM};
+
+const ROUNDS: u32 = 20;
+
+/// Counts R1 down from 32767, loading a word on every pass.
+const LOOP: &str = ".ORIG x3000\nLD R1, COUNT\nLOOP LDR R2, R1, #0\nADD R1, R1, #-1\nBRp LOOP\nHALT\n\
+                    COUNT .FILL x7FFF\n.END";
+
+/// The fastest of `ROUNDS` runs of the loop on a VM made by `new_vm`.
+fn time(new_vm: fn() -> VM) -> Duration {
+    let image = assemble(LOOP).unwrap();
+    (0..ROUNDS)
+        .map(|_| {
+            let mut vm = new_vm();
+            vm.set_io(Box::new(BufferIo::default()));
+            vm.set_diagnostics(Diagnostics::Silent);
+            vm.load_bytes(&image).unwrap();
+            let start = Instant::now();
+            vm.run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let polling = time(VM::default);
+    let fast = time(VM::non_interactive);
+    println!("device polling:  {:?}", polling);
+    println!("non-interactive: {:?}", fast);
+}
@@ -0,0 +1,138 @@
+//! Static analysis of the program in memory.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use num_traits::FromPrimitive;
+
+use crate::instruction::Instruction;
+use crate::memory::DEVICE_PAGE_ADDR;
+use crate::{TRAP, VM};
+
+/// The target of a subroutine call.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Callee {
+    /// A JSR to a known address.
+    Direct(u16),
+    /// A JSRR, whose target depends on a register at run time.
+    Indirect,
+}
+
+/// The subroutines reachable from an entry point and the calls each makes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CallGraph {
+    pub entry: u16,
+    /// The calls made by each subroutine (including the entry point),
+    /// keyed by the subroutine's address.
+    pub calls: BTreeMap<u16, BTreeSet<Callee>>,
+}
+
+impl VM {
+    /// The distinct trap vectors referenced by TRAP instructions in memory
+    /// (outside the device page), in ascending order. Known vectors are
+    /// decoded to `TRAP`; any other vector is returned as `Err(vector)`.
+    ///
+    /// Memory isn't split into code and data, so a data word that happens
+    /// to encode a TRAP instruction is counted as well.
+    pub fn used_traps(&self) -> Vec<Result<TRAP, u8>> {
+        let vectors: BTreeSet<u8> = (0..DEVICE_PAGE_ADDR as u16)
+            .map(|addr| self.memory.peek(addr))
+            /* |1111|0000|trapvect8| */
+            .filter(|&word| word & 0xFF00 == 0xF000)
+            .map(|word| word as u8)
+            .collect();
+        vectors
+            .into_iter()
+            .map(|vector| TRAP::from_u8(vector).ok_or(vector))
+            .collect()
+    }
+
+    /// Build the static call graph of the code reachable from `entry`,
+    /// following JSR targets into further subroutines. Each subroutine is
+    /// explored along every branch until a RET, HALT or computed jump.
+    /// JSRR targets can't be known without running the program, so they
+    /// are recorded as `Callee::Indirect`.
+    pub fn call_graph(&self, entry: u16) -> CallGraph {
+        let mut graph = CallGraph { entry, calls: BTreeMap::new() };
+        let mut pending = vec![entry];
+        while let Some(start) = pending.pop() {
+            if graph.calls.contains_key(&start) {
+                continue;
+            }
+            let calls = self.subroutine_calls(start);
+            for callee in &calls {
+                if let Callee::Direct(target) = *callee {
+                    pending.push(target);
+                }
+            }
+            graph.calls.insert(start, calls);
+        }
+        graph
+    }
+
+    /// The calls made by the code reachable from `start` without calling
+    /// or returning.
+    fn subroutine_calls(&self, start: u16) -> BTreeSet<Callee> {
+        let mut calls = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![start];
+        while let Some(pc) = pending.pop() {
+            if pc as usize >= DEVICE_PAGE_ADDR || !visited.insert(pc) {
+                continue;
+            }
+            let next = pc.wrapping_add(1);
+            match Instruction::decode(self.memory.peek(pc)) {
+                Instruction::Br { cond: 0, .. } => pending.push(next),
+                Instruction::Br { cond, offset } => {
+                    pending.push(next.wrapping_add(offset as u16));
+                    /* BRnzp always branches */
+                    if cond != 0b111 {
+                        pending.push(next);
+                    }
+                }
+                Instruction::Jsr { offset } => {
+                    calls.insert(Callee::Direct(next.wrapping_add(offset as u16)));
+                    pending.push(next);
+                }
+                Instruction::Jsrr { .. } => {
+                    calls.insert(Callee::Indirect);
+                    pending.push(next);
+                }
+                Instruction::Jmp { .. } | Instruction::Rti | Instruction::Res => {}
+                Instruction::Trap { vector } if vector == TRAP::HALT as u8 => {}
+                _ => pending.push(next),
+            }
+        }
+        calls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load;
+
+    #[test]
+    fn hello_world_uses_puts_and_halt() {
+        let mut vm = VM::default();
+        vm.load_file(concat!(env!("CARGO_MANIFEST_DIR"), "/hello_world.obj")).unwrap();
+        assert_eq!(vm.used_traps(), [Ok(TRAP::PUTS), Ok(TRAP::HALT)]);
+    }
+
+    #[test]
+    fn call_graph_links_two_subroutines() {
+        let vm = load(
+            ".ORIG x3000\nJSR A\nJSR B\nHALT\nA JSR B\nRET\nB JSRR R2\nRET\n.END",
+        );
+        let graph = vm.call_graph(0x3000);
+        let edges = |callees: &[Callee]| callees.iter().copied().collect::<BTreeSet<_>>();
+        assert_eq!(graph.entry, 0x3000);
+        assert_eq!(
+            graph.calls,
+            BTreeMap::from([
+                (0x3000, edges(&[Callee::Direct(0x3003), Callee::Direct(0x3005)])),
+                (0x3003, edges(&[Callee::Direct(0x3005)])),
+                (0x3005, edges(&[Callee::Indirect])),
+            ])
+        );
+    }
+}
@@ -0,0 +1,571 @@
+//! A two-pass assembler producing object images in the format `read_image`
+//! expects: a big-endian origin word followed by big-endian program words.
+//!
+//! The first pass lays out the program and records label addresses, the
+//! second pass evaluates operands against the finished symbol table.
+//! Operands of `.ORIG`, `.FILL` and `.BLKW` may be simple expressions such
+//! as `LABEL+2` or `x3000+10`. PC-relative instruction operands are either
+//! a literal offset (`#-3`, `x10`) or an expression naming the target
+//! address (`LOOP`, `DATA+1`), and must fit in their bit field. Since
+//! `.ORIG` and `.BLKW` determine the layout they are evaluated during the
+//! first pass, so any labels they use must already be defined.
+
+use std::fmt;
+
+use crate::symbols::SymbolTable;
+use crate::VM;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct AssembleError {
+    /// The 1-based source line the error was found on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Assemble LC-3 source text into an object image.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    Ok(Program::assemble(source)?.to_image())
+}
+
+/// Assemble LC-3 source text into an object image along with the text of
+/// its `.sym` symbol file.
+pub fn assemble_with_symbols(source: &str) -> Result<(Vec<u8>, String), AssembleError> {
+    let program = Program::assemble(source)?;
+    Ok((program.to_image(), program.symbols.to_string()))
+}
+
+/// Assemble a single instruction to be placed at `address`, e.g.
+/// `"ADD R0, R0, #1"`. With no labels to refer to, PC-relative operands
+/// are literal offsets such as `#-2`.
+pub fn assemble_instruction(text: &str, address: u16) -> Result<u16, AssembleError> {
+    instruction(text, address, &SymbolTable::default())
+}
+
+fn instruction(text: &str, address: u16, symbols: &SymbolTable) -> Result<u16, AssembleError> {
+    let err = |message: String| AssembleError { line: 1, message };
+    let text = strip_comment(text).trim();
+    let (op, operands) = match text.split_once(char::is_whitespace) {
+        Some((op, operands)) => (op.to_uppercase(), operands.trim()),
+        None => (text.to_uppercase(), ""),
+    };
+    if op.starts_with('.') || !is_operation(&op) {
+        return Err(err(format!("expected an instruction, found '{}'", text)));
+    }
+    encode(&op, operands, address, symbols).map_err(err)
+}
+
+impl VM {
+    /// Assemble a single instruction and poke it into memory at `addr`,
+    /// e.g. for a debugger's `poke 3000 ADD R0,R0,#1`. Operands may use
+    /// the VM's symbols.
+    pub fn assemble_poke(&mut self, addr: u16, text: &str) -> Result<(), AssembleError> {
+        let word = instruction(text, addr, &self.symbols)?;
+        self.poke(addr, word);
+        Ok(())
+    }
+
+    /// Assemble `source` and load it into memory, setting the PC to its
+    /// origin. Returns the origin.
+    pub fn load_source(&mut self, source: &str) -> Result<u16, AssembleError> {
+        let program = Program::assemble(source)?;
+        for (offset, &word) in program.words.iter().enumerate() {
+            self.memory.write(program.origin.wrapping_add(offset as u16), word);
+        }
+        self.registers.program_count = program.origin;
+        self.listing.extend(program.listing);
+        Ok(program.origin)
+    }
+
+    /// The source line that produced the word at `addr`, for programs
+    /// loaded with `load_source`. Only the first word of a multi-word
+    /// directive such as `.STRINGZ` has a line.
+    pub fn source_line(&self, addr: u16) -> Option<&str> {
+        self.listing.get(&addr).map(String::as_str)
+    }
+}
+
+/// An assembled program: its origin and the words to place there.
+pub(crate) struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    /// The address of each statement and its source line.
+    pub listing: Vec<(u16, String)>,
+    pub symbols: SymbolTable,
+}
+
+/// A source line with its comment and label stripped.
+struct Statement<'a> {
+    line: usize,
+    address: u16,
+    op: String,
+    operands: &'a str,
+}
+
+impl Program {
+    pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+        let mut symbols = SymbolTable::default();
+        let mut statements = Vec::new();
+        let mut listing = Vec::new();
+        let mut origin = None;
+        let mut address: u32 = 0;
+
+        /* first pass: lay out the program and build the symbol table */
+        for (index, source_text) in source.lines().enumerate() {
+            let line = index + 1;
+            let err = |message: String| AssembleError { line, message };
+            let text = strip_comment(source_text).trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            let (label, rest) = split_label(text);
+            if let Some(label) = label {
+                if origin.is_none() {
+                    return Err(err(format!("label '{}' before .ORIG", label)));
+                }
+                if symbols.address(label).is_some() {
+                    return Err(err(format!("duplicate label '{}'", label)));
+                }
+                symbols.insert(label, address as u16);
+            }
+            let Some(rest) = rest else { continue };
+            let (op, operands) = match rest.split_once(char::is_whitespace) {
+                Some((op, operands)) => (op.to_uppercase(), operands.trim()),
+                None => (rest.to_uppercase(), ""),
+            };
+
+            match (op.as_str(), origin) {
+                (".ORIG", None) => {
+                    let value = evaluate(operands, &symbols).map_err(err)?;
+                    origin = Some(value);
+                    address = value as u32;
+                    continue;
+                }
+                (".ORIG", Some(_)) => return Err(err("multiple .ORIG directives".to_string())),
+                (".END", _) => break,
+                (_, None) => return Err(err(format!("'{}' before .ORIG", op))),
+                _ => {}
+            }
+
+            let size = match op.as_str() {
+                ".BLKW" => evaluate(operands, &symbols).map_err(err)? as u32,
+                ".STRINGZ" => parse_string(operands).map_err(err)?.len() as u32 + 1,
+                ".FILL" => 1,
+                _ if is_operation(&op) => 1,
+                _ => return Err(err(format!("unknown operation '{}'", op))),
+            };
+            listing.push((address as u16, source_text.trim().to_string()));
+            statements.push(Statement { line, address: address as u16, op, operands });
+            address += size;
+            if address > 0x10000 {
+                return Err(err("program extends past the end of memory".to_string()));
+            }
+        }
+
+        let origin = origin.ok_or(AssembleError { line: 0, message: "missing .ORIG".to_string() })?;
+
+        /* second pass: emit words */
+        let mut words = Vec::with_capacity((address - origin as u32) as usize);
+        for statement in statements {
+            let err = |message: String| AssembleError { line: statement.line, message };
+            debug_assert_eq!(statement.address as usize, origin as usize + words.len());
+            match statement.op.as_str() {
+                ".FILL" => words.push(evaluate(statement.operands, &symbols).map_err(err)?),
+                ".BLKW" => {
+                    let count = evaluate(statement.operands, &symbols).map_err(err)?;
+                    words.extend(std::iter::repeat_n(0, count as usize));
+                }
+                ".STRINGZ" => {
+                    let string = parse_string(statement.operands).map_err(err)?;
+                    words.extend(string.iter().map(|&c| c as u16));
+                    words.push(0);
+                }
+                op => words.push(
+                    encode(op, statement.operands, statement.address, &symbols).map_err(err)?,
+                ),
+            }
+        }
+
+        Ok(Program { origin, words, listing, symbols })
+    }
+
+    pub fn to_image(&self) -> Vec<u8> {
+        std::iter::once(self.origin)
+            .chain(self.words.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect()
+    }
+}
+
+/// Remove a `;` comment, ignoring semicolons inside string literals.
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Split an optional leading label from the rest of the statement.
+fn split_label(text: &str) -> (Option<&str>, Option<&str>) {
+    let (first, rest) = match text.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, Some(rest.trim())),
+        None => (text, None),
+    };
+    if is_operation(first) {
+        (None, Some(text))
+    } else {
+        (Some(first.trim_end_matches(':')), rest)
+    }
+}
+
+fn is_operation(token: &str) -> bool {
+    let token = token.to_uppercase();
+    token.starts_with('.') || branch_condition(&token).is_some() || matches!(
+        token.as_str(),
+        "ADD" | "AND" | "NOT" | "JMP" | "RET" | "JSR" | "JSRR" | "LD" | "LDI" | "LDR" | "LEA"
+            | "ST" | "STI" | "STR" | "TRAP" | "RTI" | "NOP"
+            | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT"
+    )
+}
+
+/// The condition bits of a `BR` mnemonic (`BR`, `BRn`, `BRzp`, ...).
+/// A bare `BR` branches unconditionally.
+fn branch_condition(op: &str) -> Option<u16> {
+    let flags = op.strip_prefix("BR")?;
+    let mut cond = 0;
+    for c in flags.chars() {
+        let bit = match c {
+            'N' => 0b100,
+            'Z' => 0b010,
+            'P' => 0b001,
+            _ => return None,
+        };
+        if cond & bit != 0 {
+            return None;
+        }
+        cond |= bit;
+    }
+    Some(if cond == 0 { 0b111 } else { cond })
+}
+
+/// Encode an instruction at `address`. `op` is the upper-cased mnemonic.
+fn encode(op: &str, operands: &str, address: u16, symbols: &SymbolTable) -> Result<u16, String> {
+    let args: Vec<&str> = match operands.trim() {
+        "" => Vec::new(),
+        operands => operands.split(',').map(str::trim).collect(),
+    };
+    let expect = |count: usize| {
+        if args.len() == count {
+            Ok(())
+        } else {
+            Err(format!("{} expects {} operand(s), found {}", op, count, args.len()))
+        }
+    };
+    let reg = |i: usize| register(args[i]).ok_or_else(|| format!("expected a register, found '{}'", args[i]));
+    let offset = |i: usize, bits: u32| pc_offset(args[i], address, bits, symbols);
+
+    if let Some(cond) = branch_condition(op) {
+        expect(1)?;
+        return Ok(cond << 9 | offset(0, 9)?);
+    }
+    let word = match op {
+        "ADD" | "AND" => {
+            expect(3)?;
+            let opcode = if op == "ADD" { 0b0001 } else { 0b0101 };
+            let src = match register(args[2]) {
+                Some(sr2) => sr2,
+                None => 1 << 5 | signed_field(args[2], 5, symbols)?,
+            };
+            opcode << 12 | reg(0)? << 9 | reg(1)? << 6 | src
+        }
+        "NOT" => {
+            expect(2)?;
+            0b1001 << 12 | reg(0)? << 9 | reg(1)? << 6 | 0x3F
+        }
+        "JMP" => {
+            expect(1)?;
+            0b1100 << 12 | reg(0)? << 6
+        }
+        "RET" => {
+            expect(0)?;
+            0xC1C0
+        }
+        "JSR" => {
+            expect(1)?;
+            0b0100 << 12 | 1 << 11 | offset(0, 11)?
+        }
+        "JSRR" => {
+            expect(1)?;
+            0b0100 << 12 | reg(0)? << 6
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect(2)?;
+            let opcode = match op {
+                "LD" => 0b0010,
+                "LDI" => 0b1010,
+                "LEA" => 0b1110,
+                "ST" => 0b0011,
+                _ => 0b1011,
+            };
+            opcode << 12 | reg(0)? << 9 | offset(1, 9)?
+        }
+        "LDR" | "STR" => {
+            expect(3)?;
+            let opcode = if op == "LDR" { 0b0110 } else { 0b0111 };
+            opcode << 12 | reg(0)? << 9 | reg(1)? << 6 | signed_field(args[2], 6, symbols)?
+        }
+        "TRAP" => {
+            expect(1)?;
+            let vector = evaluate(args[0], symbols)?;
+            if vector > 0xFF {
+                return Err(format!("trap vector '{}' does not fit in 8 bits", args[0]));
+            }
+            0xF000 | vector
+        }
+        "RTI" | "NOP" | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            expect(0)?;
+            match op {
+                "RTI" => 0x8000,
+                "NOP" => 0x0000,
+                "GETC" => 0xF020,
+                "OUT" => 0xF021,
+                "PUTS" => 0xF022,
+                "IN" => 0xF023,
+                "PUTSP" => 0xF024,
+                _ => 0xF025,
+            }
+        }
+        _ => return Err(format!("unknown operation '{}'", op)),
+    };
+    Ok(word)
+}
+
+/// Parse a register name, `R0` through `R7`.
+fn register(token: &str) -> Option<u16> {
+    let digit = token.strip_prefix(['R', 'r'])?;
+    match digit.parse::<u16>() {
+        Ok(n) if n < 8 && digit.len() == 1 => Some(n),
+        _ => None,
+    }
+}
+
+/// A PC-relative offset field of `bits` bits. A literal number is the
+/// offset itself; anything else is the target address.
+fn pc_offset(operand: &str, address: u16, bits: u32, symbols: &SymbolTable) -> Result<u16, String> {
+    let offset = match parse_number(operand) {
+        Some(offset) => offset,
+        None => evaluate(operand, symbols)? as i32 - (address as i32 + 1),
+    };
+    fit_signed(offset, bits).ok_or_else(|| {
+        format!("offset {} to '{}' does not fit in {} bits", offset, operand, bits)
+    })
+}
+
+/// A signed immediate field of `bits` bits, such as ADD's imm5.
+fn signed_field(operand: &str, bits: u32, symbols: &SymbolTable) -> Result<u16, String> {
+    let value = evaluate(operand, symbols)? as i16 as i32;
+    fit_signed(value, bits)
+        .ok_or_else(|| format!("immediate '{}' does not fit in {} bits", operand, bits))
+}
+
+/// `value` truncated to a `bits`-bit two's complement field, if it fits.
+fn fit_signed(value: i32, bits: u32) -> Option<u16> {
+    let limit = 1 << (bits - 1);
+    (-limit..limit).contains(&value).then_some(value as u16 & ((1 << bits) - 1))
+}
+
+/// Evaluate an expression of numbers and labels joined by `+` and `-`.
+fn evaluate(expr: &str, symbols: &SymbolTable) -> Result<u16, String> {
+    if expr.trim().is_empty() {
+        return Err("missing operand".to_string());
+    }
+    /* split into signed terms, leaving the sign of literals like `#-3` alone */
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut sign = 1;
+    for c in expr.chars() {
+        if (c == '+' || c == '-') && !matches!(term.trim(), "" | "#") {
+            terms.push((sign, std::mem::take(&mut term)));
+            sign = if c == '-' { -1 } else { 1 };
+        } else {
+            term.push(c);
+        }
+    }
+    terms.push((sign, term));
+
+    let mut total: i32 = 0;
+    for (sign, term) in terms {
+        let term = term.trim();
+        let value = match parse_number(term) {
+            Some(value) => value,
+            None => match symbols.address(term) {
+                Some(addr) => addr as i32,
+                None => return Err(format!("undefined symbol or bad number '{}'", term)),
+            },
+        };
+        total += sign * value;
+    }
+    if !(-0x8000..=0xFFFF).contains(&total) {
+        return Err(format!("value of '{}' does not fit in 16 bits", expr));
+    }
+    Ok(total as u16)
+}
+
+/// Parse `#10`, `#-3`, `x3000`, `0x3000` or a bare decimal number.
+pub(crate) fn parse_number(token: &str) -> Option<i32> {
+    let (negative, digits, radix) = if let Some(rest) = token.strip_prefix('#') {
+        let (negative, rest) = match rest.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        (negative, rest, 10)
+    } else if let Some(rest) = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        (false, rest, 16)
+    } else if let Some(rest) = token.strip_prefix(['x', 'X']) {
+        let (negative, rest) = match rest.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        (negative, rest, 16)
+    } else {
+        let (negative, rest) = match token.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, token),
+        };
+        (negative, rest, 10)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// Parse a double-quoted string literal with C-style escapes.
+fn parse_string(operand: &str) -> Result<Vec<u8>, String> {
+    let inner = operand
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found '{}'", operand))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('"' | '\\')) => c,
+                _ => return Err("bad escape sequence in string".to_string()),
+            }
+        } else {
+            c
+        };
+        if !c.is_ascii() {
+            return Err(format!("non-ASCII character '{}' in string", c));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_fill_and_blkw_expressions() {
+        let program = Program::assemble(
+            ".ORIG x3000
+             A .FILL DATA+1
+             B .FILL x10-2
+             COUNT .BLKW B-A+2
+             DATA .FILL DATA
+             .END",
+        )
+        .unwrap();
+        assert_eq!(program.words, [0x3006, 0x000E, 0, 0, 0, 0x3005]);
+    }
+
+    #[test]
+    fn blkw_labels_must_be_defined_first() {
+        let err = Program::assemble(".ORIG x3000\n.BLKW LATER\nLATER .FILL 1\n.END").err();
+        assert_eq!(err.map(|err| err.line), Some(2));
+    }
+
+    #[test]
+    fn source_line_maps_addresses_back_to_the_source() {
+        let mut vm = VM::default();
+        let origin = vm
+            .load_source(".ORIG x3000\nAND R0, R0, #0\nLOOP ADD R0, R0, #1 ; count\nBR LOOP\n.END")
+            .unwrap();
+        assert_eq!(origin, 0x3000);
+        assert_eq!(vm.source_line(0x3001), Some("LOOP ADD R0, R0, #1 ; count"));
+        assert_eq!(vm.source_line(0x3003), None);
+    }
+
+    #[test]
+    fn symbol_file_lists_each_label() {
+        let (image, sym) = assemble_with_symbols(
+            ".ORIG x3000\nSTART AND R0, R0, #0\nLOOP ADD R0, R0, #1\nBR LOOP\nDATA .FILL 7\n.END",
+        )
+        .unwrap();
+        let unlabelled = ".ORIG x3000\nAND R0, R0, #0\nADD R0, R0, #1\nBR #-2\n.FILL 7\n.END";
+        assert_eq!(image, assemble(unlabelled).unwrap());
+        for line in ["//  START             3000", "//  LOOP              3001", "//  DATA              3003"] {
+            assert!(sym.lines().any(|l| l == line), "missing {:?} in\n{}", line, sym);
+        }
+        let symbols = SymbolTable::parse(&sym);
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.address("DATA"), Some(0x3003));
+    }
+
+    #[test]
+    fn hello_world_loads_back_with_read_image() {
+        let image = assemble(
+            ".ORIG x3000
+             LEA R0, HELLO
+             PUTS
+             HALT
+             HELLO .STRINGZ \"Hello, World!\"
+             .END",
+        )
+        .unwrap();
+        let mut memory = crate::memory::Memory::default();
+        assert_eq!(crate::read_image(&mut memory, &image[..]).unwrap(), 0x3000);
+        assert_eq!(memory.peek(0x3000), 0xE002);
+        assert_eq!(memory.peek(0x3001), 0xF022);
+        assert_eq!(memory.peek(0x3002), 0xF025);
+        let text: Vec<u16> = "Hello, World!".bytes().map(u16::from).chain([0]).collect();
+        let loaded: Vec<u16> = (0x3003..0x3003 + text.len() as u16).map(|addr| memory.peek(addr)).collect();
+        assert_eq!(loaded, text);
+    }
+
+    #[test]
+    fn assemble_poke_patches_a_running_program() {
+        let mut vm = crate::tests::load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END");
+        vm.assemble_poke(0x3000, "ADD R0, R0, #12").unwrap();
+        assert_eq!(vm.peek(0x3000), 0x102C);
+        assert_eq!(vm.step(), crate::STATUS::Continue);
+        assert_eq!(vm.register(0), 12);
+        assert!(vm.assemble_poke(0x3001, "ADD R0, R0, #99").is_err());
+        assert_eq!(vm.peek(0x3001), 0xF025);
+    }
+}
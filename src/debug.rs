@@ -0,0 +1,634 @@
+//! Debugger support: recording what an instruction changed, tracing, and
+//! stepping backwards through a journal of recent instructions.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::instruction::{Instruction, Operand};
+use crate::interrupt::{INTERRUPT_VECTOR_TABLE, PRIVILEGE_EXCEPTION};
+use crate::memory::Memory;
+use crate::registers::Registers;
+use crate::{STATUS, VM};
+
+/// A single register or memory change made by an instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Effect {
+    Register { reg: u16, old: u16, new: u16 },
+    Memory { addr: u16, old: u16, new: u16 },
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::Register { reg, old, new } =>
+                write!(f, "R{} <- {:#06x} (was {:#06x})", reg, new, old),
+            Effect::Memory { addr, old, new } =>
+                write!(f, "M[{:#06x}] <- {:#06x} (was {:#06x})", addr, new, old),
+        }
+    }
+}
+
+/// Work out the effect of an instruction from the registers before it ran
+/// and the memory's record of the last write. A memory write takes
+/// precedence, otherwise the lowest numbered changed register is reported.
+pub fn effect(before: &Registers, after: &Registers, memory: &Memory) -> Option<Effect> {
+    if let Some((addr, old)) = memory.last_write() {
+        return Some(Effect::Memory { addr, old, new: memory.peek(addr) });
+    }
+    (0..8)
+        .find(|&r| before.get(r) != after.get(r))
+        .map(|reg| Effect::Register { reg, old: before.get(reg), new: after.get(reg) })
+}
+
+/// The inputs an ADD, AND or NOT will operate on, resolved against the
+/// current registers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OperandPreview {
+    /// The source registers and their current values, in operand order.
+    pub registers: Vec<(u16, u16)>,
+    /// The immediate operand of the immediate forms of ADD/AND.
+    pub immediate: Option<i16>,
+}
+
+impl fmt::Display for OperandPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut operands: Vec<String> = self
+            .registers
+            .iter()
+            .map(|(reg, value)| format!("R{} ({:#06x})", reg, value))
+            .collect();
+        operands.extend(self.immediate.map(|imm| format!("#{}", imm)));
+        write!(f, "{}", operands.join(", "))
+    }
+}
+
+/// Everything a single step changed, for highlighting in a debugger.
+/// Changes to the PC and the condition codes aren't listed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StepDelta {
+    /// The changed registers in register order, then the changed memory
+    /// words in the order they were first written.
+    pub changes: Vec<Effect>,
+}
+
+/// One executed instruction in the structured trace.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceEvent {
+    pub pc: u16,
+    pub instruction: Instruction,
+    pub effect: Option<Effect>,
+}
+
+/// Everything needed to undo one instruction: the instruction word, the
+/// registers before it ran, and the memory word it overwrote (if any).
+/// An instruction writes at most one memory location.
+pub struct JournalEntry {
+    instr: u16,
+    registers: Registers,
+    write: Option<(u16, u16)>,
+}
+
+impl VM {
+    /// Execute one instruction while capturing whatever the enabled
+    /// debugging features (effects, trace, journal, profile) need.
+    pub(crate) fn step_recorded(&mut self) -> STATUS {
+        let pc = self.registers.program_count;
+        let instr = self.memory.peek(pc);
+        let registers = self.registers.clone();
+        self.memory.clear_last_write();
+        let status = self.execute();
+        if status == STATUS::HardInterrupt {
+            /* rolled back: nothing was executed */
+            return status;
+        }
+
+        let effect = effect(&registers, &self.registers, &self.memory);
+        if self.record_effects {
+            self.last_effect = effect;
+        }
+        let in_range = self.trace_range.as_ref().is_none_or(|range| range.contains(&pc));
+        if self.trace_capacity > 0 && in_range {
+            if self.trace.len() == self.trace_capacity {
+                self.trace.pop_front();
+            }
+            self.trace.push_back(TraceEvent { pc, instruction: Instruction::decode(instr), effect });
+        }
+        if self.history_capacity > 0 {
+            self.record_history(instr, registers);
+        }
+        if self.profiling {
+            self.record_execution(pc);
+        }
+        status
+    }
+
+    /// Record the register or memory change made by each instruction,
+    /// available afterwards from `last_effect`.
+    pub fn set_record_effects(&mut self, record: bool) {
+        self.record_effects = record;
+        self.last_effect = None;
+    }
+
+    /// The change made by the most recently executed instruction, if
+    /// effect recording is enabled and the instruction changed anything.
+    pub fn last_effect(&self) -> Option<Effect> {
+        self.last_effect
+    }
+
+    /// The source values `instr` would use if it were executed now. Only
+    /// ADD, AND and NOT are previewed; any other instruction gives an empty
+    /// preview.
+    pub fn operand_preview(&self, instr: u16) -> OperandPreview {
+        let source = |reg: u16| (reg, self.registers.get(reg));
+        match Instruction::decode(instr) {
+            Instruction::Add { sr1, src, .. } | Instruction::And { sr1, src, .. } => match src {
+                Operand::Register(sr2) => OperandPreview {
+                    registers: vec![source(sr1), source(sr2)],
+                    immediate: None,
+                },
+                Operand::Immediate(imm) => OperandPreview {
+                    registers: vec![source(sr1)],
+                    immediate: Some(imm),
+                },
+            },
+            Instruction::Not { sr, .. } => OperandPreview {
+                registers: vec![source(sr)],
+                immediate: None,
+            },
+            _ => OperandPreview::default(),
+        }
+    }
+
+    /// Where the PC will be after the instruction at the PC executes, based
+    /// on the current registers and condition codes. The built-in trap
+    /// handlers return to the following instruction, while with OS traps a
+    /// TRAP goes through the trap vector table. An RTI returns to the PC on
+    /// the supervisor stack, or in user mode enters the privilege exception
+    /// handler if there is one.
+    pub fn predict_next_pc(&self) -> u16 {
+        let pc = self.registers.program_count;
+        let next = pc.wrapping_add(1);
+        match Instruction::decode(self.memory.peek(pc)) {
+            Instruction::Br { cond, offset } if cond & self.registers.condition() != 0 =>
+                next.wrapping_add(offset as u16),
+            Instruction::Jmp { base } | Instruction::Jsrr { base } => self.registers.get(base),
+            Instruction::Jsr { offset } => next.wrapping_add(offset as u16),
+            Instruction::Trap { vector } if self.use_os_traps => self.memory.peek(vector as u16),
+            Instruction::Rti if self.registers.privilege() => self.memory.peek(self.registers.regs[6]),
+            Instruction::Rti => {
+                let entry = INTERRUPT_VECTOR_TABLE + PRIVILEGE_EXCEPTION as u16;
+                match self.memory.peek(entry) {
+                    /* no handler: an invalid operation */
+                    0 => next,
+                    handler => handler,
+                }
+            }
+            _ => next,
+        }
+    }
+
+    /// Run until the next instruction to execute is a TRAP, stopping just
+    /// before it, so that a debugger can pause at each I/O point. If the PC
+    /// is already at a TRAP that one is executed first, so repeated calls
+    /// move from one trap to the next. Returns `Continue` when stopped at a
+    /// trap, `HardInterrupt` if waiting for input, or the status that
+    /// halted the VM.
+    pub fn run_to_next_trap(&mut self) -> STATUS {
+        loop {
+            if self.halted {
+                return self.halt_reason.unwrap_or(STATUS::Halted);
+            }
+            match self.step() {
+                STATUS::Continue | STATUS::SoftInterrupt => {}
+                STATUS::HardInterrupt => return STATUS::HardInterrupt,
+                status => {
+                    self.halt(status);
+                    return status;
+                }
+            }
+            let next = self.memory.peek(self.registers.program_count);
+            if let Instruction::Trap { .. } = Instruction::decode(next) {
+                return STATUS::Continue;
+            }
+        }
+    }
+
+    /// Execute a single instruction like `step`, also returning every
+    /// register and memory word it changed. Most instructions change one
+    /// location, but traps and interrupts can change several. A word that
+    /// was written back with its old value isn't a change.
+    pub fn step_with_delta(&mut self) -> (STATUS, StepDelta) {
+        let before = self.registers.clone();
+        self.memory.start_write_log();
+        let status = self.step();
+        let writes = self.memory.take_write_log();
+
+        let mut changes: Vec<Effect> = (0..8)
+            .filter(|&reg| before.get(reg) != self.registers.get(reg))
+            .map(|reg| Effect::Register { reg, old: before.get(reg), new: self.registers.get(reg) })
+            .collect();
+        let mut seen = BTreeSet::new();
+        for (addr, old) in writes {
+            let new = self.memory.peek(addr);
+            if seen.insert(addr) && new != old {
+                changes.push(Effect::Memory { addr, old, new });
+            }
+        }
+        (status, StepDelta { changes })
+    }
+
+    /// Keep a journal of the last `capacity` instructions so that they can
+    /// be undone with `step_back`. A capacity of 0 (the default) disables
+    /// the journal. Program output cannot be taken back, so stepping back
+    /// over an instruction that printed only restores the machine state.
+    /// Each entry records just the registers and the one word written, a
+    /// few dozen bytes, rather than a full ~128KB `snapshot`.
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history_capacity = capacity;
+        while self.history.len() > capacity {
+            self.history.pop_front();
+        }
+    }
+
+    /// Label a range of addresses (e.g. "main", "stack") for the debugger.
+    /// Regions are expected not to overlap; naming a region with the same
+    /// start address as an existing one replaces it.
+    pub fn name_region(&mut self, range: Range<u16>, name: &str) {
+        self.regions.insert(range.start, (range.end, name.to_string()));
+    }
+
+    /// The name of the region containing `addr`, if any.
+    pub fn region_name(&self, addr: u16) -> Option<&str> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .filter(|(_, (end, _))| addr < *end)
+            .map(|(_, (_, name))| name.as_str())
+    }
+
+    /// Dump memory as rows of eight hex words, without device side effects.
+    /// A `; name` header line is emitted whenever the region changes.
+    pub fn hexdump(&self, range: Range<u16>) -> String {
+        let mut dump = String::new();
+        let mut region = None;
+        let mut column = 0;
+        for addr in range {
+            let name = self.region_name(addr);
+            if name != region {
+                if column != 0 {
+                    dump.push('\n');
+                }
+                if let Some(name) = name {
+                    writeln!(dump, "; {}", name).unwrap();
+                }
+                region = name;
+                column = 0;
+            }
+            if column == 0 {
+                write!(dump, "x{:04X}:", addr).unwrap();
+            }
+            write!(dump, " {:04X}", self.memory.peek(addr)).unwrap();
+            column += 1;
+            if column == 8 {
+                dump.push('\n');
+                column = 0;
+            }
+        }
+        if column != 0 {
+            dump.push('\n');
+        }
+        dump
+    }
+
+    /// Record a structured trace of the last `capacity` instructions. A
+    /// capacity of 0 (the default) disables tracing.
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        self.trace_capacity = capacity;
+        while self.trace.len() > capacity {
+            self.trace.pop_front();
+        }
+    }
+
+    /// Confine the trace to instructions whose PC falls in `range`, e.g. a
+    /// single subroutine. `None` (the default) traces everything.
+    pub fn set_trace_range(&mut self, range: Option<Range<u16>>) {
+        self.trace_range = range;
+    }
+
+    /// The recorded trace, oldest instruction first.
+    pub fn replay(&self) -> impl Iterator<Item = &TraceEvent> {
+        self.trace.iter()
+    }
+
+    fn record_history(&mut self, instr: u16, registers: Registers) {
+        if self.history.len() == self.history_capacity {
+            self.history.pop_front();
+        }
+        let write = self.memory.last_write();
+        self.history.push_back(JournalEntry { instr, registers, write });
+    }
+
+    /// Undo the most recently executed instruction. Returns false if the
+    /// journal is empty.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(entry) => {
+                self.undo(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Undo back to before the most recent subroutine call. If the last
+    /// instruction was a RET, this rewinds through the whole subroutine to
+    /// the state before its JSR/JSRR; otherwise it is the same as
+    /// `step_back`. Returns false (and changes nothing) if the journal
+    /// doesn't reach back far enough.
+    pub fn step_back_over(&mut self) -> bool {
+        let mut depth = 0;
+        for (count, entry) in self.history.iter().rev().enumerate() {
+            match Instruction::decode(entry.instr) {
+                Instruction::Jmp { base: 7 } => depth += 1,
+                Instruction::Jsr { .. } | Instruction::Jsrr { .. } if depth > 0 => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                for _ in 0..=count {
+                    self.step_back();
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    fn undo(&mut self, entry: JournalEntry) {
+        if let Some((addr, old)) = entry.write {
+            self.memory.write(addr, old);
+        }
+        self.registers = entry.registers;
+        self.clear_halt();
+    }
+}
+
+/// Fault injection, for exercising the fault handling of embedders
+/// deterministically. Only available in debug builds.
+#[cfg(debug_assertions)]
+impl VM {
+    /// Make the next access to `addr` (an instruction fetch, a load or a
+    /// store) fail with `STATUS::Fault(ExecError::MemoryFault(addr))`. The
+    /// access is refused: nothing is stored, nothing is loaded into a
+    /// register, and the PC moves past the instruction. Like any invalid
+    /// operation, this is subject to the illegal policy.
+    pub fn inject_memory_fault_at(&mut self, addr: u16) {
+        self.memory.inject_fault(addr);
+    }
+
+    /// Plant an invalid instruction at `addr`. `word` must be a RES or an
+    /// RTI instruction (which is invalid in user mode).
+    pub fn inject_bad_instruction_at(&mut self, addr: u16, word: u16) {
+        assert!(
+            matches!(word >> 12, 0b1101 | 0b1000),
+            "{:#06x} is not a RES or RTI instruction",
+            word
+        );
+        self.memory.write(addr, word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble_instruction;
+    use crate::tests::{capture_io, load};
+
+    #[test]
+    fn records_the_effect_of_add_and_st() {
+        let mut vm = load(".ORIG x3000\nADD R1, R1, #3\nST R1, DATA\nHALT\nDATA .FILL 9\n.END");
+        vm.set_record_effects(true);
+        vm.step();
+        assert_eq!(vm.last_effect(), Some(Effect::Register { reg: 1, old: 0, new: 3 }));
+        vm.step();
+        assert_eq!(vm.last_effect(), Some(Effect::Memory { addr: 0x3003, old: 9, new: 3 }));
+    }
+
+    #[test]
+    fn step_back_over_rewinds_a_whole_subroutine() {
+        let mut vm = load(
+            ".ORIG x3000
+             ADD R0, R0, #1
+             JSR SUB
+             HALT
+             SUB ADD R1, R1, #2
+             ADD R2, R2, #3
+             RET
+             .END",
+        );
+        vm.set_history_capacity(16);
+        vm.step();
+        let before = (vm.registers.regs, vm.registers.psr);
+        for _ in 0..4 {
+            vm.step();
+        }
+        assert_eq!(vm.pc(), 0x3002);
+        assert!(vm.step_back_over());
+        assert_eq!(vm.pc(), 0x3001);
+        assert_eq!((vm.registers.regs, vm.registers.psr), before);
+    }
+
+    #[test]
+    fn predicts_branches_and_jumps() {
+        let mut vm = load(".ORIG x3000\nBRz SKIP\nBRn SKIP\nJMP R3\nSKIP HALT\n.END");
+        assert_eq!(vm.predict_next_pc(), 0x3003);
+        vm.set_pc(0x3001);
+        assert_eq!(vm.predict_next_pc(), 0x3002);
+        vm.set_pc(0x3002);
+        vm.set_register(3, 0x4000);
+        assert_eq!(vm.predict_next_pc(), 0x4000);
+        vm.step();
+        assert_eq!(vm.pc(), 0x4000);
+    }
+
+    #[test]
+    fn predicts_traps_and_rti() {
+        let mut vm = load(".ORIG x3000\nOUT\nRTI\n.END");
+        assert_eq!(vm.predict_next_pc(), 0x3001);
+        vm.set_use_os_traps(true);
+        vm.poke(0x0021, 0x0500);
+        assert_eq!(vm.predict_next_pc(), 0x0500);
+        vm.step();
+        assert_eq!(vm.pc(), 0x0500);
+
+        /* user mode, with and without a privilege exception handler */
+        vm.set_pc(0x3001);
+        assert_eq!(vm.predict_next_pc(), 0x3002);
+        vm.poke(INTERRUPT_VECTOR_TABLE, 0x0600);
+        assert_eq!(vm.predict_next_pc(), 0x0600);
+
+        /* supervisor mode returns to the PC on the stack */
+        vm.registers.psr &= !0x8000;
+        vm.set_register(6, 0x2FFE);
+        vm.poke(0x2FFE, 0x3456);
+        vm.poke(0x2FFF, 0x8002);
+        assert_eq!(vm.predict_next_pc(), 0x3456);
+        vm.step();
+        assert_eq!(vm.pc(), 0x3456);
+    }
+
+    #[test]
+    fn traces_each_executed_instruction() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #2\nADD R1, R0, #3\nHALT\n.END");
+        vm.set_trace_capacity(8);
+        assert_eq!(vm.run(), STATUS::Halted);
+        let trace: Vec<_> = vm.replay().collect();
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace[1].pc, 0x3001);
+        assert_eq!(trace[1].effect, Some(Effect::Register { reg: 1, old: 0, new: 5 }));
+        assert_eq!(trace[2].instruction, Instruction::Trap { vector: 0x25 });
+    }
+
+    #[test]
+    fn hexdump_headers_name_each_region() {
+        let mut vm = load(".ORIG x3000\n.FILL 1\n.FILL 2\n.FILL 3\n.FILL 4\n.END");
+        vm.name_region(0x3000..0x3002, "code");
+        vm.name_region(0x3002..0x3004, "data");
+        assert_eq!(vm.region_name(0x3001), Some("code"));
+        assert_eq!(vm.region_name(0x3004), None);
+        assert_eq!(
+            vm.hexdump(0x3000..0x3005),
+            "; code\nx3000: 0001 0002\n; data\nx3002: 0003 0004\nx3004: 0000\n"
+        );
+    }
+
+    #[test]
+    fn run_to_next_trap_pauses_before_each_trap() {
+        let mut vm = load(
+            ".ORIG x3000\nAND R0, R0, #0\nADD R0, R0, #10\nOUT\nADD R1, R1, #1\nHALT\n.END",
+        );
+        assert_eq!(vm.run_to_next_trap(), STATUS::Continue);
+        assert_eq!(vm.pc(), 0x3002);
+        assert_eq!(vm.register(0), 10);
+        assert_eq!(vm.run_to_next_trap(), STATUS::Continue);
+        assert_eq!(vm.pc(), 0x3004);
+        assert_eq!(vm.register(1), 1);
+        assert_eq!(vm.run_to_next_trap(), STATUS::Halted);
+    }
+
+    #[test]
+    fn previews_add_and_and_not_operands() {
+        let mut vm = load(".ORIG x3000\nHALT\n.END");
+        vm.set_register(1, 3);
+        vm.set_register(2, 0xFFFF);
+        let add = assemble_instruction("ADD R0, R1, R2", 0x3000).unwrap();
+        let preview = vm.operand_preview(add);
+        assert_eq!(preview, OperandPreview { registers: vec![(1, 3), (2, 0xFFFF)], immediate: None });
+        assert_eq!(preview.to_string(), "R1 (0x0003), R2 (0xffff)");
+
+        let and = assemble_instruction("AND R0, R1, #-5", 0x3000).unwrap();
+        let preview = vm.operand_preview(and);
+        assert_eq!(preview, OperandPreview { registers: vec![(1, 3)], immediate: Some(-5) });
+        assert_eq!(preview.to_string(), "R1 (0x0003), #-5");
+
+        let not = assemble_instruction("NOT R0, R2", 0x3000).unwrap();
+        assert_eq!(vm.operand_preview(not), OperandPreview { registers: vec![(2, 0xFFFF)], immediate: None });
+
+        let ld = assemble_instruction("LD R0, #1", 0x3000).unwrap();
+        assert_eq!(vm.operand_preview(ld), OperandPreview::default());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn injected_faults_refuse_the_access() {
+        use crate::ExecError;
+        let source = ".ORIG x3000\nST R1, DATA\nLD R2, DATA\nSTI R1, PTR\nHALT\nDATA .FILL 5\nPTR .FILL x3100\n.END";
+        let fault = STATUS::Fault(ExecError::MemoryFault(0x3004));
+
+        /* a store */
+        let mut vm = load(source);
+        vm.set_register(1, 7);
+        vm.inject_memory_fault_at(0x3004);
+        assert_eq!(vm.step(), fault);
+        assert_eq!((vm.peek(0x3004), vm.pc()), (5, 0x3001));
+
+        /* a load */
+        let mut vm = load(source);
+        vm.set_register(1, 7);
+        vm.step();
+        vm.inject_memory_fault_at(0x3004);
+        assert_eq!(vm.step(), fault);
+        assert_eq!((vm.register(2), vm.condition(), vm.pc()), (0, 0b001, 0x3002));
+
+        /* the pointer of an STI, so the store never happens either */
+        let mut vm = load(source);
+        vm.set_register(1, 7);
+        vm.set_pc(0x3002);
+        vm.inject_memory_fault_at(0x3005);
+        assert_eq!(vm.step(), STATUS::Fault(ExecError::MemoryFault(0x3005)));
+        assert_eq!((vm.peek(0x3100), vm.peek(0x0000)), (0, 0));
+
+        /* an instruction fetch */
+        let mut vm = load(source);
+        vm.inject_memory_fault_at(0x3000);
+        assert_eq!(vm.step(), STATUS::Fault(ExecError::MemoryFault(0x3000)));
+        assert_eq!((vm.peek(0x3004), vm.pc()), (5, 0x3001));
+
+        /* each fault trips once */
+        assert_eq!(vm.step(), STATUS::Continue);
+    }
+
+    #[test]
+    fn trace_range_keeps_only_the_subroutine() {
+        let mut vm = load(
+            ".ORIG x3000\nJSR SUB\nADD R0, R0, #1\nHALT\nSUB ADD R1, R1, #1\nADD R1, R1, #1\nRET\n.END",
+        );
+        vm.set_trace_capacity(16);
+        vm.set_trace_range(Some(0x3003..0x3006));
+        assert_eq!(vm.run(), STATUS::Halted);
+        let pcs: Vec<u16> = vm.replay().map(|event| event.pc).collect();
+        assert_eq!(pcs, [0x3003, 0x3004, 0x3005]);
+    }
+
+    #[test]
+    fn stepping_back_twice_returns_to_after_the_first_step() {
+        let mut vm = load(
+            ".ORIG x3000\nADD R0, R0, #1\nADD R1, R0, #2\nST R1, DATA\nHALT\nDATA .FILL 0\n.END",
+        );
+        vm.set_history_capacity(4);
+        vm.step();
+        let registers = vm.registers.clone();
+        vm.step();
+        vm.step();
+        assert_eq!(vm.peek(0x3004), 3);
+
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.pc(), 0x3001);
+        assert_eq!(vm.registers.regs, registers.regs);
+        assert_eq!(vm.registers.psr, registers.psr);
+        assert_eq!(vm.peek(0x3004), 0);
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.pc(), 0x3000);
+    }
+
+    #[test]
+    fn step_delta_for_add_and_puts() {
+        let mut vm = load(".ORIG x3000\nADD R1, R1, #4\nLEA R0, TEXT\nPUTS\nHALT\nTEXT .STRINGZ \"ok\"\n.END");
+        let io = capture_io(&mut vm);
+
+        let (status, delta) = vm.step_with_delta();
+        assert_eq!(status, STATUS::Continue);
+        assert_eq!(delta.changes, [Effect::Register { reg: 1, old: 0, new: 4 }]);
+
+        vm.step();
+        /* PUTS only prints; the trap's return address in R7 is its one change */
+        let (status, delta) = vm.step_with_delta();
+        assert_eq!(status, STATUS::Continue);
+        assert_eq!(delta.changes, [Effect::Register { reg: 7, old: 0, new: 0x3003 }]);
+        assert_eq!(vm.pc(), 0x3003);
+        assert_eq!(io.borrow().output, b"ok");
+    }
+}
@@ -0,0 +1,315 @@
+//! The memory-mapped device registers. `Memory` delegates every access to
+//! one of these addresses here, so that device emulation stays separate
+//! from plain RAM.
+
+use std::collections::VecDeque;
+
+use crate::io::{Io, TerminalIo};
+
+pub const KEYBOARD_STATUS_ADDR: u16 = 0xFE00;
+/// KBSR bit 15 is set while a character is waiting in the KBDR.
+pub const KEYBOARD_READY: u16 = 1 << 15;
+/// Setting KBSR bit 14 makes a waiting character raise an interrupt.
+pub const KEYBOARD_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const KEYBOARD_DATA_ADDR: u16   = 0xFE02;
+
+pub const DISPLAY_STATUS_ADDR: u16  = 0xFE04;
+pub const DISPLAY_DATA_ADDR: u16    = 0xFE06;
+
+/// KEYBOARD_CHECK_ADDR is an address I am custom defining.
+/// It records if the program has checked the KEYBOARD_STATUS
+/// address. This is useful for the WASM code to determine
+/// when to suspend execution to await user input.
+/// (It used to live at 0xFE04, which the spec assigns to the DSR.)
+pub const KEYBOARD_CHECK_ADDR: u16  = 0xFE10;
+
+/// The Processor Status Register: privilege (bit 15), priority (bits
+/// 10-8) and the condition codes (bits 2-0). It is processor state kept in
+/// `Registers::psr`, so it isn't one of the registers here; the VM maps it.
+pub const PROCESSOR_STATUS_ADDR: u16 = 0xFFFC;
+
+/// The Machine Control Register. Clearing bit 15 (the clock enable)
+/// stops the machine, and the HALT trap clears it too.
+pub const MACHINE_CONTROL_ADDR: u16 = 0xFFFE;
+pub const CLOCK_ENABLE: u16 = 1 << 15;
+
+pub(crate) struct Devices {
+    kbsr: u16,
+    kbdr: u16,
+    kbcheck: u16,
+    /// The last character written to the DDR.
+    ddr: u16,
+    /// How many DSR reads report "not ready" after each DDR write.
+    display_delay: u32,
+    /// DSR reads left before the display is ready again.
+    display_busy: u32,
+    mcr: u16,
+    /// When disabled, KBSR reads return the stored value and never poll
+    /// for input, so a stray read of the device page cannot block.
+    keyboard_enabled: bool,
+    /// Input queued by the host (e.g. a paste), consumed before asking
+    /// the I/O device.
+    pending_input: VecDeque<u8>,
+    /// Set when a KBSR poll found no input available.
+    input_starved: bool,
+    /// How many times the program has read KBSR.
+    keyboard_polls: u64,
+    /// The character device backing the keyboard (and the I/O traps).
+    io: Box<dyn Io>,
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Devices {
+            kbsr: 0,
+            kbdr: 0,
+            kbcheck: 0,
+            ddr: 0,
+            display_delay: 0,
+            display_busy: 0,
+            mcr: CLOCK_ENABLE,
+            keyboard_enabled: true,
+            pending_input: VecDeque::new(),
+            input_starved: false,
+            keyboard_polls: 0,
+            io: Box::<TerminalIo>::default(),
+        }
+    }
+}
+
+impl Devices {
+    /// Whether `addr` is one of the device registers.
+    pub fn owns(addr: u16) -> bool {
+        matches!(
+            addr,
+            KEYBOARD_STATUS_ADDR
+                | KEYBOARD_DATA_ADDR
+                | KEYBOARD_CHECK_ADDR
+                | DISPLAY_STATUS_ADDR
+                | DISPLAY_DATA_ADDR
+                | MACHINE_CONTROL_ADDR
+        )
+    }
+
+    /// Read a device register on behalf of the program. Reading KBSR polls
+    /// the keyboard (if enabled and no character is waiting) and reading
+    /// KBDR takes the character; any other read clears the keyboard check.
+    pub fn read(&mut self, addr: u16) -> u16 {
+        if addr == KEYBOARD_STATUS_ADDR {
+            self.keyboard_polls += 1;
+        }
+        // A character that is already waiting (polled, or latched for the
+        // interrupt) stays put until the KBDR is read.
+        let ready = self.kbsr & KEYBOARD_READY != 0;
+        if addr == KEYBOARD_STATUS_ADDR && self.keyboard_enabled && !ready {
+            self.kbcheck = 1;
+            let c = self.get_char();
+            self.kbsr &= KEYBOARD_INTERRUPT_ENABLE;
+            if c != 0 {
+                self.kbsr |= KEYBOARD_READY;
+                self.kbdr = c as u16;
+            } else {
+                self.input_starved = true;
+            }
+        } else {
+            self.kbcheck = 0;
+        }
+        let val = self.peek(addr);
+        if addr == KEYBOARD_DATA_ADDR {
+            self.kbsr &= !KEYBOARD_READY;
+        }
+        if addr == DISPLAY_STATUS_ADDR {
+            self.display_busy = self.display_busy.saturating_sub(1);
+        }
+        val
+    }
+
+    /// Read a device register without any side effects.
+    pub fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            KEYBOARD_STATUS_ADDR => self.kbsr,
+            KEYBOARD_DATA_ADDR => self.kbdr,
+            KEYBOARD_CHECK_ADDR => self.kbcheck,
+            DISPLAY_STATUS_ADDR if self.display_busy > 0 => 0,
+            DISPLAY_STATUS_ADDR => 1 << 15,
+            DISPLAY_DATA_ADDR => self.ddr,
+            MACHINE_CONTROL_ADDR => self.mcr,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Write a device register on behalf of the program. Writing the DDR
+    /// displays the character (and keeps the display busy for a while if
+    /// it is slow).
+    pub fn store(&mut self, addr: u16, val: u16) {
+        self.write(addr, val);
+        if addr == DISPLAY_DATA_ADDR {
+            self.io.put_char(val as u8);
+            self.display_busy = self.display_delay;
+        }
+    }
+
+    /// Set a device register without any side effects.
+    pub fn write(&mut self, addr: u16, val: u16) {
+        match addr {
+            KEYBOARD_STATUS_ADDR => self.kbsr = val,
+            KEYBOARD_DATA_ADDR => self.kbdr = val,
+            KEYBOARD_CHECK_ADDR => self.kbcheck = val,
+            /* the DSR is read-only */
+            DISPLAY_STATUS_ADDR => {}
+            DISPLAY_DATA_ADDR => self.ddr = val,
+            MACHINE_CONTROL_ADDR => self.mcr = val,
+            _ => unreachable!(),
+        }
+    }
+
+    /// A copy of the device registers and keyboard state. The I/O device
+    /// can't be shared, so the copy gets the default terminal.
+    pub fn fork(&self) -> Devices {
+        Devices {
+            kbsr: self.kbsr,
+            kbdr: self.kbdr,
+            kbcheck: self.kbcheck,
+            ddr: self.ddr,
+            display_delay: self.display_delay,
+            display_busy: self.display_busy,
+            mcr: self.mcr,
+            keyboard_enabled: self.keyboard_enabled,
+            pending_input: self.pending_input.clone(),
+            input_starved: self.input_starved,
+            keyboard_polls: self.keyboard_polls,
+            io: Box::<TerminalIo>::default(),
+        }
+    }
+
+    /// Note a read of ordinary memory, which clears the keyboard check.
+    pub fn clear_keyboard_check(&mut self) {
+        self.kbcheck = 0;
+    }
+
+    pub fn set_display_delay(&mut self, polls: u32) {
+        self.display_delay = polls;
+        self.display_busy = 0;
+    }
+
+    pub fn set_keyboard_enabled(&mut self, enabled: bool) {
+        self.keyboard_enabled = enabled;
+    }
+
+    pub fn keyboard_enabled(&self) -> bool {
+        self.keyboard_enabled
+    }
+
+    /// Whether the keyboard wants an interrupt: interrupts are enabled in
+    /// the KBSR and a character is ready. Only input queued by the host is
+    /// latched here, so this never blocks on the I/O device.
+    pub fn keyboard_interrupt(&mut self) -> bool {
+        if !self.keyboard_enabled || self.kbsr & KEYBOARD_INTERRUPT_ENABLE == 0 {
+            return false;
+        }
+        if self.kbsr & KEYBOARD_READY == 0 {
+            if let Some(c) = self.pending_input.pop_front() {
+                self.kbsr |= KEYBOARD_READY;
+                self.kbdr = c as u16;
+            }
+        }
+        self.kbsr & KEYBOARD_READY != 0
+    }
+
+    /// The next input character: queued input first, then the I/O device.
+    pub fn get_char(&mut self) -> u8 {
+        match self.pending_input.pop_front() {
+            Some(c) => c,
+            None => self.io.get_char(),
+        }
+    }
+
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.pending_input.extend(input);
+    }
+
+    pub fn io(&mut self) -> &mut dyn Io {
+        self.io.as_mut()
+    }
+
+    pub fn set_io(&mut self, io: Box<dyn Io>) -> Box<dyn Io> {
+        std::mem::replace(&mut self.io, io)
+    }
+
+    pub fn take_input_starved(&mut self) -> bool {
+        std::mem::take(&mut self.input_starved)
+    }
+
+    pub fn keyboard_polls(&self) -> u64 {
+        self.keyboard_polls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::BufferIo;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn devices(input: &[u8]) -> (Devices, Rc<RefCell<BufferIo>>) {
+        let io = Rc::new(RefCell::new(BufferIo::new(input)));
+        let mut devices = Devices::default();
+        devices.set_io(Box::new(io.clone()));
+        (devices, io)
+    }
+
+    #[test]
+    fn owns_only_the_device_registers() {
+        let registers =
+            [KEYBOARD_STATUS_ADDR, KEYBOARD_DATA_ADDR, DISPLAY_STATUS_ADDR, DISPLAY_DATA_ADDR, MACHINE_CONTROL_ADDR];
+        for addr in registers {
+            assert!(Devices::owns(addr));
+        }
+        assert!(!Devices::owns(PROCESSOR_STATUS_ADDR));
+        assert!(!Devices::owns(0x3000));
+    }
+
+    #[test]
+    fn keyboard_registers_hand_over_a_character() {
+        let (mut devices, _) = devices(b"x");
+        assert_eq!(devices.read(KEYBOARD_STATUS_ADDR), KEYBOARD_READY);
+        assert_eq!(devices.peek(KEYBOARD_CHECK_ADDR), 1);
+        assert_eq!(devices.read(KEYBOARD_DATA_ADDR), b'x' as u16);
+        assert_eq!(devices.peek(KEYBOARD_STATUS_ADDR), 0);
+        assert_eq!(devices.peek(KEYBOARD_CHECK_ADDR), 0);
+
+        assert_eq!(devices.read(KEYBOARD_STATUS_ADDR), 0);
+        assert!(devices.take_input_starved());
+        assert_eq!(devices.keyboard_polls(), 2);
+
+        devices.write(KEYBOARD_DATA_ADDR, 0x41);
+        assert_eq!(devices.peek(KEYBOARD_DATA_ADDR), 0x41);
+    }
+
+    #[test]
+    fn display_registers_print_and_report_ready() {
+        let (mut devices, io) = devices(b"");
+        assert_eq!(devices.read(DISPLAY_STATUS_ADDR), 1 << 15);
+        devices.write(DISPLAY_STATUS_ADDR, 0);
+        assert_eq!(devices.peek(DISPLAY_STATUS_ADDR), 1 << 15);
+
+        devices.write(DISPLAY_DATA_ADDR, b'a' as u16);
+        devices.store(DISPLAY_DATA_ADDR, b'b' as u16);
+        assert_eq!(devices.peek(DISPLAY_DATA_ADDR), b'b' as u16);
+        assert_eq!(io.borrow().output, b"b");
+
+        devices.set_display_delay(2);
+        devices.store(DISPLAY_DATA_ADDR, b'c' as u16);
+        assert_eq!(devices.read(DISPLAY_STATUS_ADDR), 0);
+        assert_eq!(devices.read(DISPLAY_STATUS_ADDR), 0);
+        assert_eq!(devices.read(DISPLAY_STATUS_ADDR), 1 << 15);
+    }
+
+    #[test]
+    fn machine_control_starts_with_the_clock_enabled() {
+        let (mut devices, _) = devices(b"");
+        assert_eq!(devices.read(MACHINE_CONTROL_ADDR), CLOCK_ENABLE);
+        devices.store(MACHINE_CONTROL_ADDR, 0);
+        assert_eq!(devices.peek(MACHINE_CONTROL_ADDR), 0);
+    }
+}
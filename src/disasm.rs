@@ -0,0 +1,130 @@
+//! Disassembly of single instruction words into LC-3 assembly text.
+
+use std::fmt;
+
+use crate::assembler::assemble_instruction;
+use crate::instruction::{Instruction, Operand};
+use crate::memory::MEMORY_SIZE;
+use crate::VM;
+
+/// Disassemble a single instruction word, e.g. `0x1425` -> `"ADD R2, R0, #5"`.
+/// PC-relative offsets are rendered as signed decimal immediates.
+pub fn disassemble(instr: u16) -> String {
+    Instruction::decode(instr).to_string()
+}
+
+impl VM {
+    /// Disassemble `len` words of memory starting at `origin` into source
+    /// text that assembles back to the same words. This is a best-effort
+    /// guess at what is code: zero words, and words whose disassembly
+    /// wouldn't reassemble to the same word (such as text, or instructions
+    /// with unused bits set), are emitted as `.FILL`.
+    pub fn to_asm(&self, origin: u16, len: usize) -> String {
+        let end = MEMORY_SIZE.min(origin as usize + len);
+        let mut source = format!(".ORIG x{:04X}\n", origin);
+        for addr in origin as usize..end {
+            let addr = addr as u16;
+            let word = self.memory.peek(addr);
+            let text = disassemble(word);
+            let line = match assemble_instruction(&text, addr) {
+                Ok(assembled) if assembled == word && word != 0 => text,
+                _ => format!(".FILL x{:04X}", word),
+            };
+            source.push_str(&format!("{:<24}; x{:04X}\n", line, addr));
+        }
+        source.push_str(".END\n");
+        source
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(r) => write!(f, "R{}", r),
+            Operand::Immediate(imm) => write!(f, "#{}", imm),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Add { dr, sr1, src } => write!(f, "ADD R{}, R{}, {}", dr, sr1, src),
+            Instruction::And { dr, sr1, src } => write!(f, "AND R{}, R{}, {}", dr, sr1, src),
+            Instruction::Not { dr, sr } => write!(f, "NOT R{}, R{}", dr, sr),
+            Instruction::Br { cond: 0, .. } => write!(f, "NOP"),
+            Instruction::Br { cond, offset } => {
+                let n = if cond & 0b100 != 0 { "n" } else { "" };
+                let z = if cond & 0b010 != 0 { "z" } else { "" };
+                let p = if cond & 0b001 != 0 { "p" } else { "" };
+                write!(f, "BR{}{}{} #{}", n, z, p, offset)
+            }
+            Instruction::Jmp { base: 7 } => write!(f, "RET"),
+            Instruction::Jmp { base } => write!(f, "JMP R{}", base),
+            Instruction::Jsr { offset } => write!(f, "JSR #{}", offset),
+            Instruction::Jsrr { base } => write!(f, "JSRR R{}", base),
+            Instruction::Ld { dr, offset } => write!(f, "LD R{}, #{}", dr, offset),
+            Instruction::Ldi { dr, offset } => write!(f, "LDI R{}, #{}", dr, offset),
+            Instruction::Ldr { dr, base, offset } => write!(f, "LDR R{}, R{}, #{}", dr, base, offset),
+            Instruction::Lea { dr, offset } => write!(f, "LEA R{}, #{}", dr, offset),
+            Instruction::St { sr, offset } => write!(f, "ST R{}, #{}", sr, offset),
+            Instruction::Sti { sr, offset } => write!(f, "STI R{}, #{}", sr, offset),
+            Instruction::Str { sr, base, offset } => write!(f, "STR R{}, R{}, #{}", sr, base, offset),
+            Instruction::Trap { vector } => write!(f, "TRAP x{:02X}", vector),
+            Instruction::Rti => write!(f, "RTI"),
+            Instruction::Res => write!(f, "RES"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembles_every_opcode() {
+        let table = [
+            (0x0DFD, "BRnz #-3"),
+            (0x0000, "NOP"),
+            (0x1425, "ADD R2, R0, #5"),
+            (0x1042, "ADD R0, R1, R2"),
+            (0x2205, "LD R1, #5"),
+            (0x37FF, "ST R3, #-1"),
+            (0x4804, "JSR #4"),
+            (0x40C0, "JSRR R3"),
+            (0x54A0, "AND R2, R2, #0"),
+            (0x63BE, "LDR R1, R6, #-2"),
+            (0x7181, "STR R0, R6, #1"),
+            (0x8000, "RTI"),
+            (0x973F, "NOT R3, R4"),
+            (0xA002, "LDI R0, #2"),
+            (0xBE10, "STI R7, #16"),
+            (0xC080, "JMP R2"),
+            (0xC1C0, "RET"),
+            (0xD000, "RES"),
+            (0xE9F0, "LEA R4, #-16"),
+            (0xF025, "TRAP x25"),
+        ];
+        for (word, text) in table {
+            assert_eq!(disassemble(word), text, "x{:04X}", word);
+        }
+    }
+
+    #[test]
+    fn to_asm_reassembles_to_the_same_words() {
+        /* ADD, a branch back, LEA, text, a zero word and HALT */
+        let words = [0x1261, 0x0FFE, 0xE002, 0x0048, 0x0000, 0xF025];
+        let mut vm = VM::default();
+        for (addr, &word) in (0x3000..).zip(&words) {
+            vm.poke(addr, word);
+        }
+        let source = vm.to_asm(0x3000, words.len());
+        assert!(source.starts_with(".ORIG x3000\n"));
+        assert!(source.contains(".FILL x0048"));
+
+        let image = crate::assemble(&source).unwrap();
+        let expected: Vec<u8> =
+            [0x3000].iter().chain(&words).flat_map(|w: &u16| w.to_be_bytes()).collect();
+        assert_eq!(image, expected);
+    }
+}
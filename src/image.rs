@@ -0,0 +1,97 @@
+//! Checking object images before they are loaded.
+
+use std::fmt;
+
+use crate::memory::{DEVICE_PAGE_ADDR, MEMORY_SIZE};
+
+/// The layout of a valid object image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ImageInfo {
+    pub origin: u16,
+    pub word_count: usize,
+    /// The address just past the last word of the program.
+    pub end: u16,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LoadError {
+    /// The image is too short to hold an origin word.
+    MissingOrigin,
+    /// The image ends with half a word.
+    OddLength { len: usize },
+    /// The program runs past the end of memory.
+    Overflow { origin: u16, word_count: usize },
+    /// The program overlaps the memory-mapped device registers.
+    DeviceOverlap { origin: u16, end: u16 },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            LoadError::MissingOrigin => write!(f, "image has no origin word"),
+            LoadError::OddLength { len } => write!(f, "image length {} is not a whole number of words", len),
+            LoadError::Overflow { origin, word_count } => {
+                write!(f, "{} words at x{:04X} run past the end of memory", word_count, origin)
+            }
+            LoadError::DeviceOverlap { origin, end } => {
+                write!(f, "image x{:04X}-x{:04X} overlaps the device registers", origin, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Check that `bytes` is a well-formed object image that fits in ordinary
+/// memory, without loading it. This is stricter than loading, which
+/// silently drops a trailing odd byte and writes over the device registers.
+pub fn validate_image(bytes: &[u8]) -> Result<ImageInfo, LoadError> {
+    if bytes.len() < 2 {
+        return Err(LoadError::MissingOrigin);
+    }
+    if !bytes.len().is_multiple_of(2) {
+        return Err(LoadError::OddLength { len: bytes.len() });
+    }
+    let origin = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let word_count = bytes.len() / 2 - 1;
+
+    let end = origin as usize + word_count;
+    if end > MEMORY_SIZE {
+        return Err(LoadError::Overflow { origin, word_count });
+    }
+    if word_count > 0 && end > DEVICE_PAGE_ADDR {
+        return Err(LoadError::DeviceOverlap { origin, end: (end - 1) as u16 });
+    }
+    Ok(ImageInfo { origin, word_count, end: end as u16 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_a_valid_image() {
+        assert_eq!(
+            validate_image(&[0x30, 0x00, 0x12, 0x34, 0xF0, 0x25]),
+            Ok(ImageInfo { origin: 0x3000, word_count: 2, end: 0x3002 })
+        );
+    }
+
+    #[test]
+    fn rejects_an_oversized_image() {
+        assert_eq!(
+            validate_image(&[0xFF, 0xFF, 0, 0, 0, 0]),
+            Err(LoadError::Overflow { origin: 0xFFFF, word_count: 2 })
+        );
+        assert_eq!(
+            validate_image(&[0xFD, 0xFF, 0, 0, 0, 0]),
+            Err(LoadError::DeviceOverlap { origin: 0xFDFF, end: 0xFE00 })
+        );
+    }
+
+    #[test]
+    fn rejects_an_odd_length_image() {
+        assert_eq!(validate_image(&[0x30, 0x00, 0xF0]), Err(LoadError::OddLength { len: 3 }));
+        assert_eq!(validate_image(&[0x30]), Err(LoadError::MissingOrigin));
+    }
+}
@@ -0,0 +1,68 @@
+//! Structured decoding of instruction words.
+
+use crate::{opcode, sign_extend, OP};
+
+/// The second source operand of ADD/AND.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operand {
+    Register(u16),
+    Immediate(i16),
+}
+
+/// A decoded instruction. Register fields hold the register index (0-7)
+/// and offsets hold the sign-extended value from the instruction word.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    Add { dr: u16, sr1: u16, src: Operand },
+    And { dr: u16, sr1: u16, src: Operand },
+    Not { dr: u16, sr: u16 },
+    /* `cond` holds the n/z/p bits in the same layout as the condition register */
+    Br { cond: u16, offset: i16 },
+    /* RET is JMP R7 */
+    Jmp { base: u16 },
+    Jsr { offset: i16 },
+    Jsrr { base: u16 },
+    Ld { dr: u16, offset: i16 },
+    Ldi { dr: u16, offset: i16 },
+    Ldr { dr: u16, base: u16, offset: i16 },
+    Lea { dr: u16, offset: i16 },
+    St { sr: u16, offset: i16 },
+    Sti { sr: u16, offset: i16 },
+    Str { sr: u16, base: u16, offset: i16 },
+    Trap { vector: u8 },
+    Rti,
+    Res,
+}
+
+impl Instruction {
+    pub fn decode(instr: u16) -> Instruction {
+        let dr = (instr >> 9) & 0x7;
+        let sr = (instr >> 6) & 0x7;
+        let offset = |bits: u8| sign_extend(instr & ((1 << bits) - 1), bits) as i16;
+        let src = if (instr >> 5) & 1 != 0 {
+            Operand::Immediate(offset(5))
+        } else {
+            Operand::Register(instr & 0x7)
+        };
+
+        match opcode(instr) {
+            OP::ADD => Instruction::Add { dr, sr1: sr, src },
+            OP::AND => Instruction::And { dr, sr1: sr, src },
+            OP::NOT => Instruction::Not { dr, sr },
+            OP::BR => Instruction::Br { cond: dr, offset: offset(9) },
+            OP::JMP => Instruction::Jmp { base: sr },
+            OP::JSR if (instr >> 11) & 1 != 0 => Instruction::Jsr { offset: offset(11) },
+            OP::JSR => Instruction::Jsrr { base: sr },
+            OP::LD => Instruction::Ld { dr, offset: offset(9) },
+            OP::LDI => Instruction::Ldi { dr, offset: offset(9) },
+            OP::LDR => Instruction::Ldr { dr, base: sr, offset: offset(6) },
+            OP::LEA => Instruction::Lea { dr, offset: offset(9) },
+            OP::ST => Instruction::St { sr: dr, offset: offset(9) },
+            OP::STI => Instruction::Sti { sr: dr, offset: offset(9) },
+            OP::STR => Instruction::Str { sr: dr, base: sr, offset: offset(6) },
+            OP::TRAP => Instruction::Trap { vector: (instr & 0xFF) as u8 },
+            OP::RTI => Instruction::Rti,
+            OP::RES => Instruction::Res,
+        }
+    }
+}
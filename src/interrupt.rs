@@ -0,0 +1,210 @@
+//! Interrupts raised by the keyboard and host-provided devices.
+//!
+//! Before each instruction the VM polls the keyboard and every registered
+//! device. The keyboard asks for an interrupt (vector x80, PL4) when
+//! bit 14 of the KBSR is set and a character is ready, and the timer
+//! (vector x81, PL6) every `VM::timer_interval` instructions. The
+//! highest-priority request is serviced if its priority is above the
+//! priority in the PSR: the PSR and PC are pushed onto the supervisor
+//! stack (switching to it from the user stack if needed), the PSR is
+//! set to supervisor mode at the request's priority, and execution
+//! continues at the address in the interrupt vector table entry
+//! `x0100 + vector`.
+//!
+//! The handler returns with RTI, which pops the PC and PSR back off the
+//! stack. RTI is privileged: in user mode it raises the privilege mode
+//! violation exception (vector x00) instead. Entering an interrupt is not
+//! recorded in the step-back journal.
+
+use crate::VM;
+
+/// The interrupt vector table occupies x0100-x01FF.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+/// The keyboard's interrupt request, made while KBSR interrupts are
+/// enabled and a character is waiting.
+pub const KEYBOARD_INTERRUPT: InterruptRequest = InterruptRequest { vector: 0x80, priority: 4 };
+
+/// The timer's interrupt request. Unlike a device's, it stays pending
+/// until it can be serviced.
+pub const TIMER_INTERRUPT: InterruptRequest = InterruptRequest { vector: 0x81, priority: 6 };
+
+/// PSR bit 15 is set in user mode.
+pub(crate) const USER_MODE: u16 = 1 << 15;
+
+/// The exception raised by a privileged instruction in user mode.
+pub(crate) const PRIVILEGE_EXCEPTION: u8 = 0x00;
+
+/// A device's request for an interrupt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InterruptRequest {
+    /// The entry in the interrupt vector table holding the handler's address.
+    pub vector: u8,
+    /// The priority level, PL0 (lowest) to PL7.
+    pub priority: u8,
+}
+
+/// A peripheral that can interrupt the program.
+pub trait Device {
+    /// Called once before every instruction. A request that can't be
+    /// serviced yet (because the processor is running at an equal or
+    /// higher priority) is dropped, so a device should keep asking until
+    /// its handler has dealt with it.
+    fn poll(&mut self) -> Option<InterruptRequest>;
+}
+
+impl VM {
+    /// Attach a device that is polled for interrupt requests before each
+    /// instruction.
+    pub fn register_device(&mut self, device: Box<dyn Device>) {
+        self.peripherals.push(device);
+    }
+
+    /// Poll the keyboard and every device and service the highest-priority
+    /// request if it outranks the current priority. Every device is polled,
+    /// even if an earlier one already asked for an interrupt.
+    pub(crate) fn poll_interrupts(&mut self) {
+        let keyboard = self.memory.keyboard_interrupt().then_some(KEYBOARD_INTERRUPT);
+        let timer = self.timer_pending.then_some(TIMER_INTERRUPT);
+        let request = keyboard
+            .into_iter()
+            .chain(timer)
+            .chain(self.peripherals.iter_mut().filter_map(|device| device.poll()))
+            .max_by_key(|request| request.priority);
+        if let Some(request) = request {
+            let psr = self.registers.psr;
+            if request.priority.min(7) as u16 > self.registers.priority() {
+                if request == TIMER_INTERRUPT {
+                    self.timer_pending = false;
+                }
+                self.interrupt(request, psr);
+            }
+        }
+    }
+
+    fn interrupt(&mut self, request: InterruptRequest, psr: u16) {
+        let priority = request.priority.min(7) as u16;
+        self.enter_service_routine(psr, priority, request.vector);
+    }
+
+    /// Raise an exception: like an interrupt, but at the current priority.
+    pub(crate) fn exception(&mut self, vector: u8) {
+        let psr = self.registers.psr;
+        self.enter_service_routine(psr, self.registers.priority(), vector);
+    }
+
+    /// Save the PSR and PC on the supervisor stack and continue in
+    /// supervisor mode at the routine in the vector table.
+    fn enter_service_routine(&mut self, psr: u16, priority: u16, vector: u8) {
+        if psr & USER_MODE != 0 {
+            self.registers.saved_usp = self.registers.regs[6];
+            self.registers.regs[6] = self.registers.saved_ssp;
+        }
+        for word in [psr, self.registers.program_count] {
+            self.registers.regs[6] = self.registers.regs[6].wrapping_sub(1);
+            self.memory.write(self.registers.regs[6], word);
+        }
+        let mode = psr & !(USER_MODE | 0x0700);
+        self.registers.psr = mode | (priority << 8);
+        let entry = INTERRUPT_VECTOR_TABLE + vector as u16;
+        self.registers.program_count = self.memory.peek(entry);
+    }
+
+    /// Return from a service routine: pop the PC and PSR, switching back to
+    /// the user stack if returning to user mode.
+    pub(crate) fn return_from_interrupt(&mut self) {
+        self.registers.program_count = self.memory.read(self.registers.regs[6]);
+        let psr = self.memory.read(self.registers.regs[6].wrapping_add(1));
+        self.registers.regs[6] = self.registers.regs[6].wrapping_add(2);
+        self.registers.psr = psr;
+        if psr & USER_MODE != 0 {
+            self.registers.saved_ssp = self.registers.regs[6];
+            self.registers.regs[6] = self.registers.saved_usp;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load;
+    use crate::STATUS;
+
+    /// Asks for a single interrupt on its `polls_left`th poll.
+    struct Countdown {
+        polls_left: Option<u32>,
+    }
+
+    impl Device for Countdown {
+        fn poll(&mut self) -> Option<InterruptRequest> {
+            let left = self.polls_left.as_mut()?;
+            *left -= 1;
+            if *left > 0 {
+                return None;
+            }
+            self.polls_left = None;
+            Some(InterruptRequest { vector: 0x90, priority: 5 })
+        }
+    }
+
+    #[test]
+    fn device_interrupt_runs_its_handler() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nADD R0, R0, #1\nADD R0, R0, #1\nHALT\n.END");
+        vm.poke(INTERRUPT_VECTOR_TABLE + 0x90, 0x4000);
+        vm.poke(0x4000, 0x1B61); // ADD R5, R5, #1
+        vm.poke(0x4001, 0x8000); // RTI
+        vm.register_device(Box::new(Countdown { polls_left: Some(3) }));
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!((vm.pc(), vm.register(5), vm.register(0)), (0x4001, 1, 2));
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!((vm.register(5), vm.register(0)), (1, 3));
+    }
+
+    #[test]
+    fn keyboard_interrupt_runs_the_isr_at_its_vector() {
+        let mut vm = load(
+            ".ORIG x3000\nLD R0, IE\nSTI R0, KBSR\nADD R1, R1, #1\nHALT\n\
+             IE .FILL x4000\nKBSR .FILL xFE00\n.END",
+        );
+        vm.poke(INTERRUPT_VECTOR_TABLE + 0x80, 0x4000);
+        vm.poke(0x4000, 0xA401); // LDI R2, KBDR
+        vm.poke(0x4001, 0x8000); // RTI
+        vm.poke(0x4002, 0xFE02); // KBDR
+        vm.push_input(b"k");
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!(vm.pc(), 0x4001);
+        assert!(vm.registers.privilege());
+        assert_eq!(vm.registers.priority(), 4);
+        assert_eq!(vm.peek(vm.register(6)), 0x3002);
+
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!((vm.register(1), vm.register(2)), (1, b'k' as u16));
+    }
+
+    #[test]
+    fn timer_interrupt_fires_after_the_tenth_instruction() {
+        let source = format!(".ORIG x3000\n{}HALT\n.END", "ADD R0, R0, #1\n".repeat(15));
+        let mut vm = load(&source);
+        vm.poke(INTERRUPT_VECTOR_TABLE + 0x81, 0x4000);
+        vm.poke(0x4000, 0x1A20); // ADD R5, R0, #0
+        vm.poke(0x4001, 0x8000); // RTI
+        vm.timer_interval = Some(10);
+
+        for _ in 0..10 {
+            vm.step();
+        }
+        assert_eq!(vm.pc(), 0x300A);
+        vm.step();
+        assert_eq!(vm.pc(), 0x4001);
+
+        assert_eq!(vm.run(), STATUS::Halted);
+        /* the handler saw exactly ten ADDs done */
+        assert_eq!((vm.register(5), vm.register(0)), (10, 15));
+    }
+}
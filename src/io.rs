@@ -1,27 +1,260 @@
-/// IO functionality for terminals and JS-WASM interop.
+//! IO functionality for terminals and JS-WASM interop.
 
+/// The character device the VM reads input from and writes output to.
+/// `get_char` returns 0 when no input is available.
+pub trait Io {
+    fn get_char(&mut self) -> u8;
+    fn put_char(&mut self, c: u8);
+
+    /// Whether the input has ended for good (e.g. a closed pipe), as
+    /// opposed to no input being available yet. Only meaningful after
+    /// `get_char` returned 0.
+    fn at_eof(&self) -> bool {
+        false
+    }
+}
+
+/// A shared device can be installed while the caller keeps a handle to
+/// it, e.g. to inspect a `BufferIo`'s output after a run.
+impl<T: Io + ?Sized> Io for std::rc::Rc<std::cell::RefCell<T>> {
+    fn get_char(&mut self) -> u8 {
+        self.borrow_mut().get_char()
+    }
+
+    fn put_char(&mut self, c: u8) {
+        self.borrow_mut().put_char(c)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.borrow().at_eof()
+    }
+}
+
+/// An in-memory device: input is read from `input` (0 once it runs out)
+/// and output is appended to `output`. Useful for scripted runs and tests.
+#[derive(Clone, Debug, Default)]
+pub struct BufferIo {
+    pub input: std::collections::VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl BufferIo {
+    pub fn new(input: &[u8]) -> Self {
+        BufferIo { input: input.iter().copied().collect(), output: Vec::new() }
+    }
+}
+
+impl Io for BufferIo {
+    fn get_char(&mut self) -> u8 {
+        self.input.pop_front().unwrap_or(0)
+    }
+
+    fn put_char(&mut self, c: u8) {
+        self.output.push(c);
+    }
+
+    fn at_eof(&self) -> bool {
+        self.input.is_empty()
+    }
+}
+
+/// A sink for program output, such as a log file. Anything implementing
+/// `std::io::Write` is an `Output`; write errors are ignored.
+pub trait Output {
+    fn put_char(&mut self, c: u8);
+}
+
+impl<W: std::io::Write> Output for W {
+    fn put_char(&mut self, c: u8) {
+        self.write_all(&[c]).ok();
+    }
+}
+
+/// Forwards output to both an `Io` device and an extra `Output` sink.
+/// Input comes from the `Io` device only.
+pub struct TeeIo {
+    primary: Box<dyn Io>,
+    extra: Box<dyn Output>,
+}
+
+impl TeeIo {
+    pub fn new(primary: Box<dyn Io>, extra: Box<dyn Output>) -> Self {
+        TeeIo { primary, extra }
+    }
+
+    /// Split the tee back into its two halves.
+    pub fn into_inner(self) -> (Box<dyn Io>, Box<dyn Output>) {
+        (self.primary, self.extra)
+    }
+}
+
+impl Io for TeeIo {
+    fn get_char(&mut self) -> u8 {
+        self.primary.get_char()
+    }
+
+    fn put_char(&mut self, c: u8) {
+        self.primary.put_char(c);
+        self.extra.put_char(c);
+    }
+
+    fn at_eof(&self) -> bool {
+        self.primary.at_eof()
+    }
+}
+
+/// Reads input a whole line at a time, so that a program building a line
+/// with GETC sees it only once Enter is pressed, after any backspace
+/// (BS or DEL) editing. With `echo` set, typed characters and erasures
+/// are written back to the device as the line is edited, since a raw
+/// terminal doesn't do that itself. Output passes straight through.
+pub struct LineIo {
+    inner: Box<dyn Io>,
+    echo: bool,
+    /// The line being typed.
+    editing: Vec<u8>,
+    /// The finished line, not yet read by the program.
+    ready: std::collections::VecDeque<u8>,
+}
+
+impl LineIo {
+    pub fn new(inner: Box<dyn Io>, echo: bool) -> Self {
+        LineIo { inner, echo, editing: Vec::new(), ready: std::collections::VecDeque::new() }
+    }
+
+    pub fn into_inner(self) -> Box<dyn Io> {
+        self.inner
+    }
+
+    /// Read from the device until a line is complete or no more input is
+    /// available.
+    fn fill(&mut self) {
+        loop {
+            match self.inner.get_char() {
+                0 => {
+                    // Hand over a final unterminated line once input ends.
+                    if self.inner.at_eof() {
+                        self.ready.extend(self.editing.drain(..));
+                    }
+                    return;
+                }
+                0x08 | 0x7F => {
+                    if self.editing.pop().is_some() && self.echo {
+                        b"\x08 \x08".iter().for_each(|&c| self.inner.put_char(c));
+                    }
+                }
+                c => {
+                    self.editing.push(c);
+                    if self.echo {
+                        self.inner.put_char(c);
+                    }
+                    if c == b'\n' || c == b'\r' {
+                        self.ready.extend(self.editing.drain(..));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Io for LineIo {
+    fn get_char(&mut self) -> u8 {
+        if self.ready.is_empty() {
+            self.fill();
+        }
+        self.ready.pop_front().unwrap_or(0)
+    }
+
+    fn put_char(&mut self, c: u8) {
+        self.inner.put_char(c)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.ready.is_empty() && self.editing.is_empty() && self.inner.at_eof()
+    }
+}
+
+/// The terminal on unix, or the JS terminal emulator on wasm.
+///
+/// On unix, output is flushed after every character when stdout is a TTY
+/// so that interactive programs stay responsive. When stdout is a pipe or
+/// a file, output is fully buffered instead and only flushed when the
+/// program asks for input or the VM is dropped.
 #[cfg(target_family = "unix")]
-pub fn get_char() -> u8 {
-    use std::io::Read;
-    std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .unwrap_or(0)
+pub struct TerminalIo {
+    stdout: std::io::BufWriter<Box<dyn std::io::Write>>,
+    interactive: bool,
+    /// Set once stdin has been closed.
+    eof: bool,
 }
 
-#[cfg(target_family = "wasm")]
-pub fn get_char() -> u8 {
-    getChar() as u8
+#[cfg(target_family = "unix")]
+impl Default for TerminalIo {
+    fn default() -> Self {
+        use std::io::IsTerminal;
+        TerminalIo::with_writer(Box::new(std::io::stdout()), std::io::stdout().is_terminal())
+    }
 }
 
 #[cfg(target_family = "unix")]
-pub fn put_char(c: u8) {
-    use std::io::Write;
+impl TerminalIo {
+    /// Write output to `writer` instead of stdout, flushing every character
+    /// if `interactive`.
+    fn with_writer(writer: Box<dyn std::io::Write>, interactive: bool) -> Self {
+        TerminalIo { stdout: std::io::BufWriter::new(writer), interactive, eof: false }
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl Io for TerminalIo {
+    fn get_char(&mut self) -> u8 {
+        use std::io::Write;
+        use std::io::Read;
+        // Make sure any prompt is visible before blocking on input.
+        self.stdout.flush().ok();
+        let mut buf = [0u8; 1];
+        match std::io::stdin().read(&mut buf) {
+            Ok(1) => buf[0],
+            Ok(_) => {
+                self.eof = true;
+                0
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn put_char(&mut self, c: u8) {
+        use std::io::Write;
+        self.stdout.write_all(&[c]).ok();
+        if self.interactive {
+            self.stdout.flush().ok();
+        }
+    }
+
+    fn at_eof(&self) -> bool {
+        self.eof
+    }
+}
+
+#[cfg(target_family = "wasm")]
+#[derive(Default)]
+pub struct TerminalIo;
+
+#[cfg(target_family = "wasm")]
+impl Io for TerminalIo {
+    fn get_char(&mut self) -> u8 {
+        get_char()
+    }
 
-    let mut stdout = std::io::stdout().lock();
-    stdout.write(&[c]).ok();
-    stdout.flush().ok();
+    fn put_char(&mut self, c: u8) {
+        put_char(c)
+    }
+}
+
+#[cfg(target_family = "wasm")]
+pub fn get_char() -> u8 {
+    getChar() as u8
 }
 
 #[cfg(target_family = "wasm")]
@@ -35,4 +268,57 @@ pub fn put_char(c: u8) {
 extern "C" {
     fn getChar() -> u32;
     fn putChar(val: u8);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the writes that reach the underlying sink.
+    #[cfg(target_family = "unix")]
+    struct CountingWriter(std::rc::Rc<std::cell::Cell<usize>>);
+
+    #[cfg(target_family = "unix")]
+    impl std::io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn terminal_output_is_coalesced_unless_interactive() {
+        use std::{cell::Cell, rc::Rc};
+        for (interactive, expected) in [(false, 1), (true, 100)] {
+            let writes = Rc::new(Cell::new(0));
+            let mut io = TerminalIo::with_writer(Box::new(CountingWriter(writes.clone())), interactive);
+            for _ in 0..100 {
+                io.put_char(b'x');
+            }
+            drop(io);
+            assert_eq!(writes.get(), expected);
+        }
+    }
+
+    #[test]
+    fn getc_sees_the_line_after_backspace_editing() {
+        use std::{cell::RefCell, rc::Rc};
+        let device = Rc::new(RefCell::new(BufferIo::new(b"cax\x08t\n")));
+        let mut vm = crate::tests::load(
+            ".ORIG x3000\nGETC\nADD R1, R0, #0\nGETC\nADD R2, R0, #0\nGETC\nADD R3, R0, #0\n\
+             GETC\nADD R4, R0, #0\nHALT\n.END",
+        );
+        vm.set_io(Box::new(LineIo::new(Box::new(device.clone()), true)));
+        assert_eq!(vm.run(), crate::STATUS::Halted);
+
+        let line: Vec<u8> = (1..=4).map(|r| vm.register(r) as u8).collect();
+        assert_eq!(line, b"cat\n");
+        /* the erased character is rubbed out on screen */
+        assert_eq!(device.borrow().output, b"cax\x08 \x08t\n");
+    }
+}
@@ -1,13 +1,53 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::ops::Range;
 
 use enum_primitive_derive::Primitive;
 use num_traits::FromPrimitive;
 
+mod analysis;
+mod assembler;
+mod debug;
+mod devices;
+mod disasm;
+mod image;
+mod instruction;
+mod interrupt;
 mod io;
+mod memmap;
 mod memory;
+mod ops;
+mod profile;
 mod registers;
+mod sandbox;
+mod snapshot;
+mod symbols;
+mod types;
 
+pub use analysis::{CallGraph, Callee};
+pub use assembler::{assemble, assemble_instruction, assemble_with_symbols, AssembleError};
+pub use debug::{Effect, OperandPreview, StepDelta, TraceEvent};
+pub use disasm::disassemble;
+pub use image::{validate_image, ImageInfo, LoadError};
+pub use instruction::{Instruction, Operand};
+pub use interrupt::{Device, InterruptRequest, INTERRUPT_VECTOR_TABLE, KEYBOARD_INTERRUPT, TIMER_INTERRUPT};
+pub use io::{BufferIo, Io, LineIo, Output, TeeIo, TerminalIo};
+pub use memmap::ParseError;
+pub use memory::{Memory, DEVICE_PAGE_ADDR, DEVICE_PAGE_SIZE};
+pub use sandbox::{equivalent, Limits, RunResult};
+pub use snapshot::SnapshotError;
+pub use symbols::SymbolTable;
+pub use types::{Addr, Reg};
+#[cfg(feature = "extensions")]
+pub use registers::Registers;
+
+/// Handler for the reserved opcode, given the instruction word.
+#[cfg(feature = "extensions")]
+pub type ReservedHandler = Box<dyn FnMut(u16, &mut Registers, &mut Memory)>;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Eq, PartialEq, Primitive)]
 enum OP {
     BR   = 0b0000,  /* branch */
@@ -28,31 +68,220 @@ enum OP {
     TRAP = 0b1111,  /* execute trap */
 }
 
-#[derive(Eq, PartialEq, Primitive)]
-enum TRAP {
+impl OP {
+    fn name(&self) -> &'static str {
+        match self {
+            OP::BR => "BR",
+            OP::ADD => "ADD",
+            OP::LD => "LD",
+            OP::ST => "ST",
+            OP::JSR => "JSR",
+            OP::AND => "AND",
+            OP::LDR => "LDR",
+            OP::STR => "STR",
+            OP::RTI => "RTI",
+            OP::NOT => "NOT",
+            OP::LDI => "LDI",
+            OP::STI => "STI",
+            OP::JMP => "JMP",
+            OP::RES => "RES",
+            OP::LEA => "LEA",
+            OP::TRAP => "TRAP",
+        }
+    }
+}
+
+/// The operation encoded in the top four bits of an instruction word.
+fn opcode(instr: u16) -> OP {
+    /* every 4-bit nibble maps to an OP variant */
+    OP::from_u16(instr >> 12).unwrap()
+}
+
+/// Where the standard LC-3 operating system image starts executing.
+pub const OS_ENTRY: u16 = 0x0200;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Primitive)]
+pub enum TRAP {
     GETC  = 0x20,  /* get character from keyboard, not echoed onto the terminal */
     OUT   = 0x21,  /* output a character */
     PUTS  = 0x22,  /* output a word string */
     IN    = 0x23,  /* get character from keyboard, echoed onto the terminal */
     PUTSP = 0x24,  /* output a byte string */
     HALT  = 0x25,  /* halt the program */
+    CLOCK = 0x26,  /* (extension) instructions executed so far, low word in R0 and high word in R1 */
+    RAND  = 0x27,  /* (extension) a pseudo-random word in R0, see VM::seed_rng */
 }
 
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum STATUS {
     Halted,
+    /// An invalid operation: an unknown trap vector, RTI in user mode
+    /// (without an OS to handle it), or the reserved opcode.
+    Fault(ExecError),
     Continue,
     SoftInterrupt,
     HardInterrupt,
+    /// R6 grew above the configured stack base (too many pops).
+    StackUnderflow { sp: u16 },
+    /// R6 dropped below the configured stack limit (too many pushes).
+    StackOverflow { sp: u16 },
+    /// A JMP/JSRR targeted 0x0000 or the device page (see the wild jump guard).
+    WildJump { target: u16 },
+    /// A store address wrapped around the address space (see the address
+    /// wrap guard). `address` is where the store would have gone.
+    AddressWrap { address: u16 },
+    /// A user-mode store into the trap vector table (see the trap table
+    /// guard).
+    TrapTableWrite { addr: u16 },
+    /// A bounded run executed its maximum number of instructions.
+    StepLimitReached,
+    /// A bounded run produced its maximum amount of output.
+    OutputLimitReached,
 }
 
-#[cfg(target_family = "wasm")]
+impl STATUS {
+    /// A numeric code for the status, for hosts such as JS that can't
+    /// match on the enum: 0 Continue, 1 Halted, 2 Fault, 3 SoftInterrupt,
+    /// 4 HardInterrupt, 5 StackUnderflow, 6 StackOverflow, 7 WildJump,
+    /// 8 AddressWrap, 9 StepLimitReached, 10 OutputLimitReached,
+    /// 11 TrapTableWrite.
+    pub fn code(self) -> u8 {
+        match self {
+            STATUS::Continue => 0,
+            STATUS::Halted => 1,
+            STATUS::Fault(_) => 2,
+            STATUS::SoftInterrupt => 3,
+            STATUS::HardInterrupt => 4,
+            STATUS::StackUnderflow { .. } => 5,
+            STATUS::StackOverflow { .. } => 6,
+            STATUS::WildJump { .. } => 7,
+            STATUS::AddressWrap { .. } => 8,
+            STATUS::StepLimitReached => 9,
+            STATUS::OutputLimitReached => 10,
+            STATUS::TrapTableWrite { .. } => 11,
+        }
+    }
+}
+
+/// Why an instruction could not be executed, carried by `STATUS::Fault`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExecError {
+    /// The instruction word didn't decode to an opcode.
+    InvalidOpcode(u16),
+    /// A TRAP to a vector with no built-in routine.
+    UnknownTrap(u8),
+    /// The reserved opcode (0b1101), with no reserved handler installed.
+    ReservedOp,
+    /// RTI in user mode, with no privilege exception handler to run.
+    PrivilegeViolation,
+    /// An access to an address set up to fault with
+    /// `VM::inject_memory_fault_at` (debug builds only).
+    MemoryFault(u16),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::InvalidOpcode(instr) => write!(f, "invalid operation: x{:04X}", instr),
+            ExecError::UnknownTrap(vector) => write!(f, "Unknown TRAP: x{:02X}", vector),
+            ExecError::ReservedOp => write!(f, "Invalid operation: RESERVED"),
+            ExecError::PrivilegeViolation => write!(f, "Privilege mode violation: RTI"),
+            ExecError::MemoryFault(addr) => write!(f, "Memory fault at x{:04X}", addr),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// What to do on an invalid operation: an unknown trap vector, RTI, or the
+/// reserved opcode.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IllegalPolicy {
+    /// Stop with `STATUS::Fault`.
+    #[default]
+    Halt,
+    /// Report it through the diagnostics and carry on with the next
+    /// instruction, as if it were a NOP.
+    Skip,
+}
+
+/// Bounds for the optional stack-pointer heuristic. Programs conventionally
+/// use R6 as a stack pointer growing downward from `base` towards `limit`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackGuard {
+    pub base: u16,
+    pub limit: u16,
+}
+
+/// Where the VM's own messages (HALT, invalid operations, ...) are sent.
+/// These are kept separate from the program's output.
 #[derive(Default)]
-#[wasm_bindgen::prelude::wasm_bindgen]
+pub enum Diagnostics {
+    /// Standard error on unix; discarded on wasm.
+    #[default]
+    Stderr,
+    Silent,
+    Custom(Box<dyn FnMut(&str)>),
+}
+
+#[derive(Default)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
 pub struct VM {
-    pub halted: bool,
+    halted: bool,
+    halt_reason: Option<STATUS>,
+    /// The number of instructions executed, for profiling and timers. See
+    /// `reset_counter`.
+    pub instructions_executed: u64,
+    /// Raise a timer interrupt (vector x81, PL6) each time
+    /// `instructions_executed` reaches a multiple of this. `None` (the
+    /// default) or zero disables the timer.
+    pub timer_interval: Option<u64>,
+    /// A timer interrupt is waiting for the priority to drop below PL6.
+    timer_pending: bool,
     memory: memory::Memory,
     registers: registers::Registers,
+    stack_guard: Option<StackGuard>,
+    trap_preserves_r7: bool,
+    eof_halt: bool,
+    /// The prompt of an IN waiting for input has been shown.
+    in_prompted: bool,
+    /// The state of the RAND trap's generator, kept per VM so that runs
+    /// (and snapshots) are reproducible.
+    rng_state: u64,
+    /// Dispatch TRAPs through the trap vector table to a loaded OS instead
+    /// of the built-in handlers.
+    use_os_traps: bool,
+    wild_jump_guard: bool,
+    illegal_policy: IllegalPolicy,
+    address_wrap_guard: bool,
+    trap_table_guard: bool,
+    /// Return addresses of the subroutine calls in progress, tracked while
+    /// the return guard is enabled.
+    call_stack: Option<Vec<u16>>,
+    symbols: SymbolTable,
+    /// Source lines by address, for programs loaded from source.
+    listing: BTreeMap<u16, String>,
+    record_effects: bool,
+    last_effect: Option<Effect>,
+    history: VecDeque<debug::JournalEntry>,
+    history_capacity: usize,
+    trace: VecDeque<TraceEvent>,
+    trace_capacity: usize,
+    /// Only trace instructions whose PC falls in this range.
+    trace_range: Option<Range<u16>>,
+    regions: BTreeMap<u16, (u16, String)>,
+    profiling: bool,
+    profile: HashMap<u16, u64>,
+    diagnostics: Diagnostics,
+    input_wait_hook: Option<Box<dyn FnMut()>>,
+    peripherals: Vec<Box<dyn interrupt::Device>>,
+    #[cfg(feature = "extensions")]
+    reserved_handler: Option<ReservedHandler>,
+    #[cfg(target_family = "wasm")]
+    poll_yield_threshold: u32,
+    #[cfg(target_family = "wasm")]
+    polls_since_yield: u32,
 }
 
 #[cfg(target_family = "wasm")]
@@ -73,15 +302,7 @@ impl VM {
     /// This will return true for a "SoftInterrupt" (polling for user input)
     /// and false for a "HardInterrupt" (blocking for user input or halted)
     pub fn run_wasm(&mut self) -> bool {
-        while !self.halted {
-            match self.step() {
-                STATUS::Halted => {self.halted = true;},
-                STATUS::Continue => {},
-                STATUS::SoftInterrupt => return true,
-                STATUS::HardInterrupt => return false,
-            }
-        }
-        false
+        self.run_until() == STATUS::SoftInterrupt
     }
 
     pub fn load_wasm(&mut self, image: &[u8]) -> bool {
@@ -95,279 +316,826 @@ impl VM {
 
         self.memory = new_memory;
         self.registers = new_registers;
-        self.halted = false;
+        self.clear_halt();
         true
     }
-}
 
-#[cfg(target_family = "unix")]
-#[derive(Default)]
-pub struct VM {
-    pub halted: bool,
-    memory: memory::Memory,
-    registers: registers::Registers,
+    /// Load another image on top of what is already in memory, e.g. a user
+    /// program after an OS, returning its origin (or nothing if the image
+    /// is invalid). Only sets the PC to the origin if `set_pc` is true.
+    pub fn load_image_at(&mut self, image: &[u8], set_pc: bool) -> Option<u16> {
+        self.load_reader_at(image, set_pc).ok()
+    }
+
+    /// Only yield back to JS after this many KBSR polls, rather than on
+    /// every poll. Tight polling loops otherwise round-trip through JS on
+    /// each iteration. The default (and minimum) is 1.
+    pub fn set_poll_yield_threshold(&mut self, polls: u32) {
+        self.poll_yield_threshold = polls;
+        self.polls_since_yield = 0;
+    }
+
+    /// Execute a single instruction, returning its `STATUS::code`. Unlike
+    /// `run_wasm` this tells a fault apart from a HALT; the faulting
+    /// instruction is the one just before the PC.
+    pub fn step_wasm(&mut self) -> u8 {
+        if self.halted {
+            return self.halt_reason.unwrap_or(STATUS::Halted).code();
+        }
+        let status = self.step();
+        match status {
+            STATUS::Continue | STATUS::SoftInterrupt | STATUS::HardInterrupt => {}
+            status => self.halt(status),
+        }
+        status.code()
+    }
+
+    /// Skip invalid operations instead of halting on them.
+    pub fn set_skip_illegal(&mut self, skip: bool) {
+        let policy = if skip { IllegalPolicy::Skip } else { IllegalPolicy::Halt };
+        self.set_illegal_policy(policy);
+    }
+
+    #[wasm_bindgen::prelude::wasm_bindgen(getter = pc)]
+    pub fn pc_wasm(&self) -> u16 {
+        self.pc()
+    }
+
+    #[wasm_bindgen::prelude::wasm_bindgen(getter)]
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Setting `halted` from JS stops the VM (e.g. while a new image is
+    /// loaded); clearing it resumes execution.
+    #[wasm_bindgen::prelude::wasm_bindgen(setter)]
+    pub fn set_halted(&mut self, halted: bool) {
+        if halted {
+            self.halted = true;
+        } else {
+            self.clear_halt();
+        }
+    }
 }
 
 
 impl VM {
+    /// A VM for batch runs of compute-only programs. Its memory is built
+    /// without keyboard polling, so reads never check for (or block on)
+    /// input. The GETC/IN traps still read from the I/O device.
+    pub fn non_interactive() -> Self {
+        VM { memory: Memory::non_interactive(), ..VM::default() }
+    }
+
+    /// A copy of this VM -- memory, registers and settings -- that runs
+    /// independently of it, e.g. to run one program against many inputs.
+    /// Host callbacks can't be shared, so the copy uses the default
+    /// terminal I/O, has no input wait hook, devices or reserved opcode
+    /// handler, and falls back to the default diagnostics if they were
+    /// custom. Debugger history, traces and profiles start out empty.
+    pub fn fork(&self) -> VM {
+        let diagnostics = match self.diagnostics {
+            Diagnostics::Silent => Diagnostics::Silent,
+            _ => Diagnostics::Stderr,
+        };
+        VM {
+            halted: self.halted,
+            halt_reason: self.halt_reason,
+            instructions_executed: self.instructions_executed,
+            timer_interval: self.timer_interval,
+            timer_pending: self.timer_pending,
+            memory: self.memory.fork(),
+            registers: self.registers.clone(),
+            stack_guard: self.stack_guard,
+            trap_preserves_r7: self.trap_preserves_r7,
+            eof_halt: self.eof_halt,
+            rng_state: self.rng_state,
+            use_os_traps: self.use_os_traps,
+            wild_jump_guard: self.wild_jump_guard,
+            illegal_policy: self.illegal_policy,
+            address_wrap_guard: self.address_wrap_guard,
+            trap_table_guard: self.trap_table_guard,
+            call_stack: self.call_stack.clone(),
+            symbols: self.symbols.clone(),
+            listing: self.listing.clone(),
+            record_effects: self.record_effects,
+            history_capacity: self.history_capacity,
+            trace_capacity: self.trace_capacity,
+            trace_range: self.trace_range.clone(),
+            regions: self.regions.clone(),
+            profiling: self.profiling,
+            diagnostics,
+            #[cfg(target_family = "wasm")]
+            poll_yield_threshold: self.poll_yield_threshold,
+            ..VM::default()
+        }
+    }
+
+    /// Load an object image file, setting the PC to its origin. Memory
+    /// outside of the image is left alone, so several non-overlapping
+    /// images can be loaded.
     pub fn load_file(&mut self, path: &str) -> std::io::Result<()> {
-        let file = BufReader::new(File::open(path)?);
-        match read_image(&mut self.memory, file) {
-            Ok(addr) => {
-                self.registers.program_count = addr;
-                Ok(())
-            },
-            Err(e) => Err(e),
+        self.load_file_at(path, true).map(|_| ())
+    }
+
+    /// Load an object image file, returning its origin. Only sets the PC
+    /// to the origin if `set_pc` is true, so that when loading several
+    /// images (e.g. an OS and a user program) just one picks the entry.
+    pub fn load_file_at(&mut self, path: &str, set_pc: bool) -> std::io::Result<u16> {
+        self.load_reader_at(File::open(path)?, set_pc)
+    }
+
+    /// Load an object image from any reader, e.g. stdin, setting the PC to
+    /// its origin. Returns the origin.
+    pub fn load_reader(&mut self, reader: impl Read) -> std::io::Result<u16> {
+        self.load_reader_at(reader, true)
+    }
+
+    /// Load an object image from an in-memory buffer, setting the PC to its
+    /// origin. Returns the origin.
+    pub fn load_bytes(&mut self, image: &[u8]) -> std::io::Result<u16> {
+        self.load_reader(image)
+    }
+
+    /// Like `load_file_at`, reading the image from any reader.
+    pub fn load_reader_at(&mut self, reader: impl Read, set_pc: bool) -> std::io::Result<u16> {
+        let addr = read_image(&mut self.memory, BufReader::new(reader))?;
+        if set_pc {
+            self.registers.program_count = addr;
+        }
+        Ok(addr)
+    }
+
+    /// Boot the standard LC-3 operating system (e.g. `lc3os.obj`): load the
+    /// OS image at its origin, start in supervisor mode at `OS_ENTRY`, and
+    /// dispatch TRAPs through the OS's trap vector table rather than the
+    /// built-in handlers. Load the user program first, since loading sets
+    /// the PC; the OS boot code then drops to user mode at x3000.
+    pub fn boot_os(&mut self, os_image: &[u8]) -> std::io::Result<()> {
+        read_image(&mut self.memory, BufReader::new(os_image))?;
+        self.registers.program_count = OS_ENTRY;
+        self.registers.psr &= !interrupt::USER_MODE;
+        self.use_os_traps = true;
+        Ok(())
+    }
+
+    /// Load a `.sym` symbol file, adding its symbols to the VM's table.
+    pub fn load_sym_file(&mut self, path: &str) -> std::io::Result<()> {
+        let symbols = SymbolTable::read(BufReader::new(File::open(path)?))?;
+        self.symbols.extend(symbols);
+        Ok(())
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// The status that halted the VM: `Halted` for a HALT trap, `Fault`
+    /// for an invalid operation, or the signal raised by one of the guards.
+    /// `None` while running or if the VM was stopped externally.
+    pub fn halt_reason(&self) -> Option<STATUS> {
+        self.halt_reason
+    }
+
+    /// Clear the halted flag (and the halt reason) so that execution can
+    /// resume from the current PC. This also restarts the clock if the
+    /// program stopped it through the MCR.
+    pub fn clear_halt(&mut self) {
+        self.halted = false;
+        self.halt_reason = None;
+        let mcr = self.memory.peek(devices::MACHINE_CONTROL_ADDR);
+        if mcr & devices::CLOCK_ENABLE == 0 {
+            self.memory.write(devices::MACHINE_CONTROL_ADDR, mcr | devices::CLOCK_ENABLE);
         }
     }
 
-    pub fn run(&mut self) {
+    /// Zero `instructions_executed`, e.g. before timing a section of a
+    /// program.
+    pub fn reset_counter(&mut self) {
+        self.instructions_executed = 0;
+    }
+
+    fn halt(&mut self, reason: STATUS) {
+        self.halted = true;
+        self.halt_reason = Some(reason);
+    }
+
+    /// Run until the program stops, returning why: `Halted` for a HALT,
+    /// `Fault` for an invalid operation, or the status raised by one of
+    /// the guards. If the VM was stopped externally this is `Halted`.
+    pub fn run(&mut self) -> STATUS {
         while !self.halted {
             match self.step() {
-                STATUS::Halted => {self.halted = true;},
-                _ => {}
+                STATUS::Continue | STATUS::SoftInterrupt | STATUS::HardInterrupt => {}
+                status => {
+                    self.halt(status);
+                    break;
+                }
             }
         }
+        self.halt_reason.unwrap_or(STATUS::Halted)
     }
 
-    pub fn step(&mut self) -> STATUS {
-        let (instr, op) = self.registers.next(&mut self.memory);
-        let op = match op {
-            Some(op) => op,
-            None => {
-                #[cfg(target_family = "unix")]
-                println!("invalid operation");
-                return STATUS::Halted
+    /// Run until the program is interrupted or stops, for frontends that
+    /// resume cooperatively. Returns `SoftInterrupt` when it polled for
+    /// input, `HardInterrupt` when it is blocked on input (the instruction
+    /// is rolled back, see `resume_with_input`), or the status that halted
+    /// it. `run` is the blocking equivalent.
+    pub fn run_until(&mut self) -> STATUS {
+        while !self.halted {
+            match self.step() {
+                STATUS::Continue => {}
+                status @ (STATUS::SoftInterrupt | STATUS::HardInterrupt) => return status,
+                status => {
+                    self.halt(status);
+                    return status;
+                }
             }
-        };
+        }
+        self.halt_reason.unwrap_or(STATUS::Halted)
+    }
 
-        match op {
-            OP::ADD => {
-                /* |0001| DR|SR1|0|00|SR2|
-                   |0001| DR|SR1|1| IMM5 | */
-                let dr = (instr >> 9) & 0x7;
-                let sr1 = (instr >> 6) & 0x7;
-                let imm_flag = (instr >> 5) & 1 != 0;
-
-                let value = if imm_flag {
-                    sign_extend(instr & 0x1F, 5)
-                } else {
-                    let sr2 = instr & 0x7;
-                    self.registers.get(sr2)
-                };
-                self.registers.set(dr, self.registers.get(sr1).wrapping_add(value));
+    /// Run at most `budget` instructions and yield back to the caller.
+    /// Returns the status of the last step along with the number of
+    /// instructions actually executed, counted as `instructions_executed`
+    /// counts them, so a faulting instruction isn't included. Exhausting
+    /// the budget is not an error -- the status is simply `Continue` -- so
+    /// this can be used to multiplex several VMs on a single thread.
+    pub fn run_slice(&mut self, budget: u64) -> (STATUS, u64) {
+        let start = self.instructions_executed;
+        let executed = |vm: &VM| vm.instructions_executed - start;
+        while executed(self) < budget {
+            if self.halted {
+                return (self.halt_reason.unwrap_or(STATUS::Halted), executed(self));
             }
-            OP::AND => {
-                /* |0001| DR|SR1|0|00|SR2|
-                   |0001| DR|SR1|1| IMM5 | */
-                let dr = (instr >> 9) & 0x7;
-                let sr1 = (instr >> 6) & 0x7;
-                let imm_flag = (instr >> 5) & 1 != 0;
-
-                let value = if imm_flag {
-                    sign_extend(instr & 0x1F, 5)
-                } else {
-                    let sr2 = instr & 0x7;
-                    self.registers.get(sr2)
-                };
-                self.registers.set(dr, self.registers.get(sr1) & value);
-            }
-            OP::NOT => {
-                /* |1001| DR| SR|111111| */
-                let dr = (instr >> 9) & 0x7;
-                let sr = (instr >> 6) & 0x7;
-                self.registers.set(dr, !self.registers.get(sr));
-            }
-            OP::BR => {
-                /* |0000|N|Z|P|PCoffset9| */
-                let pc_offset = sign_extend(instr & 0x1FF, 9);
-                let cond_flag = (instr >> 9) & 0x7;
-                if (cond_flag & self.registers.condition) != 0 {
-                    self.registers.program_count =
-                        self.registers.program_count.wrapping_add(pc_offset);
+            match self.step() {
+                STATUS::Continue => {}
+                // Yield so the caller can provide input; a HardInterrupt
+                // rolled the instruction back, so it isn't counted.
+                status @ (STATUS::HardInterrupt | STATUS::SoftInterrupt) => {
+                    return (status, executed(self))
                 }
-            }
-            OP::JMP => {
-                /* |1100|000| SR|000000| (RET when SR=7) */
-                let sr = (instr >> 6) & 0x7;
-                self.registers.program_count = self.registers.get(sr);
-            }
-            OP::JSR => {
-                /*  JSR: |0100|1|  PCoffset11 | */
-                /* JSRR: |0100|0|00| SR|000000| */
-                let long_flag = (instr >> 11) & 1 != 0;
-                self.registers.r7 = self.registers.program_count;
-                if long_flag {
-                    let long_pc_offset = sign_extend(instr & 0x7FF, 11);
-                    self.registers.program_count =
-                        self.registers.program_count.wrapping_add(long_pc_offset);
-                } else {
-                    let sr = (instr >> 6) & 0x7;
-                    self.registers.program_count = self.registers.get(sr);
+                status => {
+                    self.halt(status);
+                    return (status, executed(self));
                 }
             }
-            OP::LD => {
-                /* |0010| DR|PCoffset9| */
-                let dr = (instr >> 9) & 0x7;
-                let pc_offset = sign_extend(instr & 0x1FF, 9);
-                let address = self.registers.program_count.wrapping_add(pc_offset);
-                self.registers.set(dr, self.memory.read(address));
-            }
-            OP::LDI => {
-                /* |1010| DR|PCoffset9| */
-                let dr = (instr >> 9) & 0x7;
-                let pc_offset = sign_extend(instr & 0x1FF, 9);
-                /* add pc_offset to the current PC, look at that memory location to get the final address */
-                let address = self.memory.read(self.registers.program_count.wrapping_add(pc_offset));
-                self.registers.set(dr, self.memory.read(address));
-            }
-            OP::LDR => {
-                /* |0110| DR| SR|offset6| */
-                let dr = (instr >> 9) & 0x7;
-                let sr = (instr >> 6) & 0x7;
-                let offset = sign_extend(instr & 0x3F, 6);
-                let address = self.registers.get(sr).wrapping_add(offset);
-                self.registers.set(dr, self.memory.read(address));
-            }
-            OP::LEA => {
-                /* |1110| DR|PCoffset9| */
-                let dr = (instr >> 9) & 0x7;
-                let pc_offset = sign_extend(instr & 0x1FF, 9);
-                self.registers.set(dr, self.registers.program_count.wrapping_add(pc_offset));
-            }
-            OP::ST => {
-                /* |0011| SR|PCoffset9| */
-                let sr = (instr >> 9) & 0x7;
-                let pc_offset = sign_extend(instr & 0x1FF, 9);
-                self.memory.write(
-                    self.registers.program_count.wrapping_add(pc_offset),
-                    self.registers.get(sr)
-                );
+        }
+        (STATUS::Continue, executed(self))
+    }
+
+    /// Run at most `max` instructions, to keep a runaway program from
+    /// looping forever. Like `run_slice` this stops early on a halt and on
+    /// soft/hard interrupts, but running out of steps is reported as
+    /// `StepLimitReached`.
+    pub fn run_steps(&mut self, max: u64) -> (u64, STATUS) {
+        match self.run_slice(max) {
+            (STATUS::Continue, executed) => (executed, STATUS::StepLimitReached),
+            (status, executed) => (executed, status),
+        }
+    }
+
+    /// Feed `c` to a program suspended on input and continue running it.
+    /// When a GETC/IN trap (or a KBSR poll) finds no input, the VM returns
+    /// `HardInterrupt` with the instruction rolled back; this queues the
+    /// character so the pending instruction receives it when it re-runs.
+    /// Returns `HardInterrupt` when the program waits for input again, or
+    /// the status that stopped it.
+    pub fn resume_with_input(&mut self, c: u8) -> STATUS {
+        self.push_input(&[c]);
+        while !self.halted {
+            match self.step() {
+                STATUS::Continue | STATUS::SoftInterrupt => {}
+                STATUS::HardInterrupt => return STATUS::HardInterrupt,
+                status => {
+                    self.halt(status);
+                    return status;
+                }
             }
-            OP::STI => {
-                /* |1011| SR|PCoffset9| */
-                let sr = (instr >> 9) & 0x7;
-                let pc_offset = sign_extend(instr & 0x1FF, 9);
-                let address = self.memory.read(self.registers.program_count.wrapping_add(pc_offset));
-                self.memory.write(address, self.registers.get(sr));
+        }
+        self.halt_reason.unwrap_or(STATUS::Halted)
+    }
+
+    /// Enable (or disable with `None`) the stack-pointer heuristic. While
+    /// enabled, any instruction that changes R6 to a value outside of
+    /// `limit..=base` stops execution with a stack status.
+    pub fn set_stack_guard(&mut self, guard: Option<StackGuard>) {
+        self.stack_guard = guard;
+    }
+
+    /// Move the memory out of the VM, leaving fresh memory in its place.
+    /// The memory carries its devices with it (the I/O device, keyboard
+    /// state and device registers). The PSR is processor state and stays
+    /// with the registers.
+    pub fn take_memory(&mut self) -> Memory {
+        std::mem::take(&mut self.memory)
+    }
+
+    /// Read a word of memory without triggering any device side effects.
+    pub fn peek(&self, addr: impl Into<Addr>) -> u16 {
+        match addr.into().0 {
+            devices::PROCESSOR_STATUS_ADDR => self.registers.psr,
+            addr => self.memory.peek(addr),
+        }
+    }
+
+    /// Write a word of memory directly, e.g. from a debugger. A write to a
+    /// device register sets it without any device side effects.
+    pub fn poke(&mut self, addr: impl Into<Addr>, val: u16) {
+        match addr.into().0 {
+            devices::PROCESSOR_STATUS_ADDR => self.registers.psr = val,
+            addr => self.memory.write(addr, val),
+        }
+    }
+
+    /// The value of a general purpose register.
+    pub fn get(&self, reg: Reg) -> u16 {
+        self.registers.get(reg.index())
+    }
+
+    /// The value of register `r` (0-7).
+    pub fn register(&self, r: u16) -> u16 {
+        self.registers.get(r)
+    }
+
+    /// Set register `r` (0-7), updating the condition codes as an
+    /// instruction writing it would.
+    pub fn set_register(&mut self, r: u16, value: u16) {
+        self.registers.set(r, value);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.registers.program_count
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.registers.program_count = pc;
+    }
+
+    /// The condition codes: 0b100 (n), 0b010 (z) or 0b001 (p).
+    pub fn condition(&self) -> u16 {
+        self.registers.condition()
+    }
+
+    /// Queue input, e.g. a block of pasted text. Each KBSR poll (or
+    /// GETC/IN) takes the next queued character before asking the I/O
+    /// device, so a whole block can be consumed without a round-trip to
+    /// the host per character. While keyboard interrupts are enabled
+    /// (KBSR bit 14), queued characters also raise them.
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.memory.push_input(input);
+    }
+
+    /// Replace the VM's memory, e.g. with one taken from another VM.
+    /// Registers are left untouched.
+    pub fn set_memory(&mut self, memory: Memory) {
+        self.memory = memory;
+    }
+
+    /// Move the words in `from` so that they start at `to`, clearing
+    /// whatever part of `from` the moved block doesn't cover. If the PC is
+    /// inside `from` it moves along with the code.
+    ///
+    /// Images carry no relocation information, so nothing inside the block
+    /// is fixed up. PC-relative references within the block keep working,
+    /// but references between the block and the rest of memory (in either
+    /// direction), and absolute addresses stored in `.FILL`s, will not.
+    pub fn relocate(&mut self, from: Range<u16>, to: u16) {
+        let words: Vec<u16> = from.clone().map(|addr| self.memory.peek(addr)).collect();
+        let moved = to..to.wrapping_add(words.len() as u16);
+        for addr in from.clone() {
+            if !moved.contains(&addr) {
+                self.memory.write(addr, 0);
             }
-            OP::STR => {
-                /* |0111| SR| DR|offset6| */
-                let sr = (instr >> 9) & 0x7;
-                let dr = (instr >> 6) & 0x7;
-                let offset = sign_extend(instr & 0x3F, 6);
-                self.memory.write(
-                    self.registers.get(dr).wrapping_add(offset),
-                    self.registers.get(sr)
-                );
+        }
+        for (offset, &word) in words.iter().enumerate() {
+            self.memory.write(to.wrapping_add(offset as u16), word);
+        }
+        let pc = self.registers.program_count;
+        if from.contains(&pc) {
+            self.registers.program_count = to.wrapping_add(pc - from.start);
+        }
+    }
+
+    /// Model a slow display: after each character written to the DDR, the
+    /// DSR reports "not ready" for the next `polls` reads, so programs have
+    /// to poll it before writing the next character. The default of 0
+    /// means the display is always ready.
+    pub fn set_display_delay(&mut self, polls: u32) {
+        self.memory.set_display_delay(polls);
+    }
+
+    /// Enable or disable the keyboard device. With the keyboard disabled,
+    /// reading KBSR simply returns the stored value (normally 0) instead of
+    /// polling for input; this is useful for purely computational programs.
+    pub fn set_keyboard_enabled(&mut self, enabled: bool) {
+        self.memory.set_keyboard_enabled(enabled);
+    }
+
+    /// How many times the program has read KBSR. A count far above the
+    /// number of characters consumed points at a busy-wait input loop.
+    pub fn keyboard_polls(&self) -> u64 {
+        self.memory.keyboard_polls()
+    }
+
+    /// TRAP saves the return address in R7 so that a trap routine can RET.
+    /// The built-in trap handlers complete immediately and never RET, so
+    /// this saving can optionally be skipped to leave R7 untouched.
+    /// By default R7 is clobbered, matching the real hardware.
+    pub fn set_trap_preserves_r7(&mut self, preserve: bool) {
+        self.trap_preserves_r7 = preserve;
+    }
+
+    /// Dispatch TRAPs through the trap vector table at x0000-x00FF: R7
+    /// gets the return address and the PC the vector's entry, so that a
+    /// loaded OS can implement (or a program override) the trap routines
+    /// in LC-3 code. Off by default, using the built-in handlers; see also
+    /// `boot_os`.
+    pub fn set_use_os_traps(&mut self, enabled: bool) {
+        self.use_os_traps = enabled;
+    }
+
+    /// Halt when GETC/IN finds that the input has ended for good (see
+    /// `Io::at_eof`), e.g. a finite pipe that closed, instead of suspending
+    /// to wait for input that will never come. Off by default.
+    pub fn set_eof_halt(&mut self, enabled: bool) {
+        self.eof_halt = enabled;
+    }
+
+    /// Seed the generator behind the RAND trap (x27). The same seed gives
+    /// the same sequence; the default seed is 0.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng_state = seed;
+    }
+
+    /// The next word from the RAND generator (splitmix64, which copes with
+    /// any state, including the default of 0).
+    fn next_random(&mut self) -> u16 {
+        self.rng_state = self.rng_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as u16
+    }
+
+    /// The raw contents of the device register page starting at
+    /// `DEVICE_PAGE_ADDR`, read without polling any device.
+    pub fn device_page(&self) -> [u16; DEVICE_PAGE_SIZE] {
+        let mut page = self.memory.device_page();
+        page[devices::PROCESSOR_STATUS_ADDR as usize - DEVICE_PAGE_ADDR] = self.registers.psr;
+        page
+    }
+
+    /// Enable the wild jump guard: a JMP/JSRR in user mode to 0x0000 or into
+    /// the device register page (almost always an uninitialized base
+    /// register) stops execution with `STATUS::WildJump` instead of
+    /// jumping. Off by default.
+    pub fn set_wild_jump_guard(&mut self, enabled: bool) {
+        self.wild_jump_guard = enabled;
+    }
+
+    fn is_wild_jump(&self, target: u16) -> bool {
+        self.wild_jump_guard
+            && (target == 0 || target as usize >= DEVICE_PAGE_ADDR)
+            && !self.registers.privilege()
+    }
+
+    /// Choose whether invalid operations halt the VM (the default) or are
+    /// skipped.
+    pub fn set_illegal_policy(&mut self, policy: IllegalPolicy) {
+        self.illegal_policy = policy;
+    }
+
+    /// Report an invalid operation and apply the illegal policy.
+    fn illegal(&mut self, error: ExecError) -> STATUS {
+        self.diagnostic(&error.to_string());
+        match self.illegal_policy {
+            IllegalPolicy::Halt => STATUS::Fault(error),
+            IllegalPolicy::Skip => STATUS::Continue,
+        }
+    }
+
+    /// Enable the trap table guard: a store into the trap vector table
+    /// (x0000-x00FF) while in user mode stops execution with
+    /// `STATUS::TrapTableWrite` instead of storing. Only an operating
+    /// system has any business writing there. Off by default.
+    pub fn set_trap_table_guard(&mut self, enabled: bool) {
+        self.trap_table_guard = enabled;
+    }
+
+    fn is_trap_table_write(&self, addr: u16) -> bool {
+        self.trap_table_guard && addr < 0x0100 && !self.registers.privilege()
+    }
+
+    /// Enable the return guard: track subroutine calls (JSR/JSRR) and traps
+    /// and emit a diagnostic warning when a RET jumps somewhere that isn't
+    /// the return address of a call in progress, e.g. a bare RET with
+    /// R7 == 0.
+    /// Execution carries on regardless. Off by default.
+    pub fn set_return_guard(&mut self, enabled: bool) {
+        self.call_stack = enabled.then(Vec::new);
+    }
+
+    /// The return addresses of the calls in progress, innermost last, or
+    /// `None` if the return guard is disabled.
+    pub fn call_stack(&self) -> Option<&[u16]> {
+        self.call_stack.as_deref()
+    }
+
+    /// Enable the address wrap guard: an ST/STR whose address computation
+    /// wraps past 0xFFFF (or below 0x0000) stops execution with
+    /// `STATUS::AddressWrap` instead of storing. Off by default, since the
+    /// hardware simply wraps.
+    pub fn set_address_wrap_guard(&mut self, enabled: bool) {
+        self.address_wrap_guard = enabled;
+    }
+
+    fn is_address_wrap(&self, base: u16, offset: u16) -> bool {
+        let address = base as i32 + offset as i16 as i32;
+        self.address_wrap_guard && !(0..=0xFFFF).contains(&address)
+    }
+
+    /// Disassemble the instruction at the PC, i.e. the next instruction to
+    /// be executed. This neither advances the PC nor touches the devices.
+    pub fn current_disasm(&self) -> String {
+        disassemble(self.memory.peek(self.registers.program_count))
+    }
+
+    /// The PC, the word there and the name of its opcode (e.g. `"ADD"`),
+    /// for a compact status display. Like `current_disasm` this has no
+    /// side effects.
+    pub fn fetch_preview(&self) -> (u16, u16, &'static str) {
+        let pc = self.registers.program_count;
+        let word = self.memory.peek(pc);
+        (pc, word, opcode(word).name())
+    }
+
+    /// Execute a single instruction like `step`, also returning the decoded
+    /// form of the instruction that was executed, which is the first
+    /// instruction of the service routine if an interrupt was taken. The
+    /// instruction is `None` if the clock was stopped or if it was rolled
+    /// back to wait for input.
+    pub fn step_decoded(&mut self) -> (STATUS, Option<Instruction>) {
+        match self.step_word() {
+            (STATUS::HardInterrupt, _) => (STATUS::HardInterrupt, None),
+            (status, word) => (status, word.map(Instruction::decode)),
+        }
+    }
+
+    /// Replace the character device used for the keyboard and the I/O
+    /// traps (the terminal by default), returning the previous one.
+    pub fn set_io(&mut self, io: Box<dyn Io>) -> Box<dyn Io> {
+        self.memory.set_io(io)
+    }
+
+    /// Also send all program output to `extra` (e.g. a log file), on top of
+    /// the current character device. Input is unaffected.
+    pub fn tee_output(&mut self, extra: Box<dyn Output>) {
+        let primary = self.memory.set_io(Box::<TerminalIo>::default());
+        self.memory.set_io(Box::new(TeeIo::new(primary, extra)));
+    }
+
+    /// Hand input to the program a line at a time, after backspace editing,
+    /// rather than keystroke by keystroke. See `LineIo`.
+    pub fn line_buffer_input(&mut self, echo: bool) {
+        let inner = self.memory.set_io(Box::<TerminalIo>::default());
+        self.memory.set_io(Box::new(LineIo::new(inner, echo)));
+    }
+
+    /// Set a hook called whenever the program waits on input that isn't
+    /// available: a GETC/IN trap about to return `HardInterrupt`, or a
+    /// KBSR poll that found no key. A GUI can use this to show a cursor.
+    pub fn set_input_wait_hook(&mut self, hook: Box<dyn FnMut()>) {
+        self.input_wait_hook = Some(hook);
+    }
+
+    /// Implement the reserved opcode (0b1101) with a Rust handler, e.g. to
+    /// prototype a custom instruction. Without a handler the reserved
+    /// opcode halts the VM.
+    #[cfg(feature = "extensions")]
+    pub fn set_reserved_handler(&mut self, handler: ReservedHandler) {
+        self.reserved_handler = Some(handler);
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.diagnostics = diagnostics;
+    }
+
+    fn diagnostic(&mut self, message: &str) {
+        match &mut self.diagnostics {
+            #[cfg(target_family = "unix")]
+            Diagnostics::Stderr => eprintln!("{}", message),
+            #[cfg(not(target_family = "unix"))]
+            Diagnostics::Stderr => {}
+            Diagnostics::Silent => {}
+            Diagnostics::Custom(sink) => sink(message),
+        }
+    }
+
+    pub fn step(&mut self) -> STATUS {
+        self.step_word().0
+    }
+
+    /// Service any interrupt and execute one instruction, also returning
+    /// the word that was executed, or `None` if the clock was stopped.
+    fn step_word(&mut self) -> (STATUS, Option<u16>) {
+        // The clock was stopped before this step, e.g. by poking the MCR
+        // from outside, so nothing is fetched.
+        if self.clock_stopped() {
+            return (STATUS::Halted, None);
+        }
+        self.poll_interrupts();
+        let word = self.memory.peek(self.registers.program_count);
+        (self.step_instruction(), Some(word))
+    }
+
+    fn clock_stopped(&self) -> bool {
+        self.memory.peek(devices::MACHINE_CONTROL_ADDR) & devices::CLOCK_ENABLE == 0
+    }
+
+    /// The rest of `step`, once any interrupt has been entered: execute the
+    /// instruction at the PC and do the bookkeeping.
+    fn step_instruction(&mut self) -> STATUS {
+        let recording = self.record_effects
+            || self.history_capacity > 0
+            || self.trace_capacity > 0
+            || self.profiling;
+        #[cfg(debug_assertions)]
+        let before = self.memory.has_faults().then(|| self.registers.clone());
+        let status = if recording {
+            self.step_recorded()
+        } else {
+            self.execute()
+        };
+        #[cfg(debug_assertions)]
+        let status = match self.memory.take_tripped_fault() {
+            Some(addr) => {
+                /* the access was refused, so the instruction has no effect
+                   beyond moving past it */
+                if let Some(mut registers) = before {
+                    registers.program_count = registers.program_count.wrapping_add(1);
+                    self.registers = registers;
+                }
+                self.illegal(ExecError::MemoryFault(addr))
             }
-            OP::TRAP => {
-                /* |1111|0000|trapvec8| */
-                self.registers.r7 = self.registers.program_count;
-                match TRAP::from_u16(instr & 0xFF) {
-                    Some(TRAP::GETC) => {
-                        let c = io::get_char();
-                        if c == 0 {
-                            // If we get a null character, we roll back the
-                            // instruction and suspend program execution to
-                            // await user input.
-                            self.registers.program_count -= 1;
-                            return STATUS::HardInterrupt;
-                        } else {
-                            self.registers.set(0, c as u16);
-                        }
-                    }
-                    Some(TRAP::OUT) => {
-                        io::put_char(self.registers.r0 as u8);
-                    }
-                    Some(TRAP::PUTS) => {
-                        let mut c = self.registers.r0;
-                        while self.memory.read(c) != 0 {
-                            io::put_char(self.memory.read(c) as u8);
-                            c += 1;
-                        }
-                    }
-                    Some(TRAP::IN) => {
-                        #[cfg(target_family = "unix")]
-                        println!("Enter a character: ");
-
-                        let c = io::get_char();
-                        if c == 0 {
-                            // If we get a null character, we roll back the
-                            // instruction and suspend program execution to
-                            // await user input.
-                            self.registers.program_count -= 1;
-                            return STATUS::HardInterrupt;
-                        } else {
-                            io::put_char(c);
-                            self.registers.set(0, c as u16);
-                        }
-                    }
-                    Some(TRAP::PUTSP) => {
-                        /* one char per byte (two bytes per word)
-                           here we need to swap back to
-                           big endian format */
-                        let mut c = self.registers.r0;
-                        while self.memory.read(c) != 0 {
-                            let c1 = self.memory.read(c) & 0xFF;
-                            io::put_char(c1 as u8);
-                            let c2 = self.memory.read(c) >> 8;
-                            if c2 != 0 { io::put_char(c2 as u8); };
-                            c += 1;
-                        }
-                    }
-                    Some(TRAP::HALT) => {
-                        #[cfg(target_family = "unix")]
-                        println!("HALT");
-
-                        return STATUS::Halted;
-                    }
-                    None => {
-                        #[cfg(target_family = "unix")]
-                        println!("Unknown TRAP");
-                        return STATUS::Halted;
-                    }
+            None => status,
+        };
+        // A HardInterrupt rolled the instruction back and a fault never
+        // executed, so neither counts.
+        if !matches!(status, STATUS::HardInterrupt | STATUS::Fault(_)) {
+            self.instructions_executed += 1;
+            if let Some(interval) = self.timer_interval {
+                if self.instructions_executed.is_multiple_of(interval) {
+                    self.timer_pending = true;
                 }
             }
-            OP::RES => {
-                #[cfg(target_family = "unix")]
-                println!("Invalid operation: RESERVED");
-                return STATUS::Halted;
+        }
+        // A HardInterrupt means GETC/IN found no input.
+        if status == STATUS::HardInterrupt || self.memory.take_input_starved() {
+            if let Some(hook) = &mut self.input_wait_hook {
+                hook();
             }
-            OP::RTI => {
-                #[cfg(target_family = "unix")]
-                println!("Invalid operation: RTI");
-                return STATUS::Halted;
+        }
+        status
+    }
+
+    pub(crate) fn execute(&mut self) -> STATUS {
+        let stack_pointer = self.registers.regs[6];
+        let instr = self.registers.fetch(&mut self.memory);
+        match ops::DISPATCH[(instr >> 12) as usize](self, instr) {
+            STATUS::Continue => {}
+            status => return status,
+        }
+
+        /* Clearing the clock enable bit of the MCR stops the machine. The
+           instruction that cleared it completes, and nothing after it is
+           fetched. */
+        if self.clock_stopped() {
+            self.diagnostic("HALT");
+            return STATUS::Halted;
+        }
+
+        if let Some(guard) = self.stack_guard {
+            let sp = self.registers.regs[6];
+            if sp != stack_pointer {
+                if sp > guard.base {
+                    return STATUS::StackUnderflow { sp };
+                }
+                if sp < guard.limit {
+                    return STATUS::StackOverflow { sp };
+                }
             }
-        };
+        }
 
         #[cfg(target_family = "wasm")]
         if self.memory.kbstatus() != 0 {
-            return STATUS::SoftInterrupt;
+            self.polls_since_yield += 1;
+            if self.polls_since_yield >= self.poll_yield_threshold.max(1) {
+                self.polls_since_yield = 0;
+                return STATUS::SoftInterrupt;
+            }
         }
         STATUS::Continue
     }
+
+    /// Pop the call that a RET to `target` returns from, warning if there
+    /// is no such call.
+    fn check_return(&mut self, target: u16) {
+        let Some(calls) = &mut self.call_stack else { return };
+        match calls.iter().rposition(|&addr| addr == target) {
+            /* returning past inner calls unwinds them too */
+            Some(depth) => calls.truncate(depth),
+            None => {
+                let message = format!("warning: RET to x{:04X}, which is not a return address", target);
+                self.diagnostic(&message);
+            }
+        }
+    }
+
+    /// Clear the clock enable bit of the MCR, as the LC-3 OS's HALT routine
+    /// does, so that reads of the MCR show the machine has stopped.
+    /// `clear_halt` starts the clock again.
+    pub(crate) fn stop_clock(&mut self) {
+        let mcr = self.memory.peek(devices::MACHINE_CONTROL_ADDR);
+        self.memory.write(devices::MACHINE_CONTROL_ADDR, mcr & !devices::CLOCK_ENABLE);
+    }
+
+    /// Load a word on behalf of an instruction. The PSR is processor state
+    /// rather than memory, so reads of its address return `Registers::psr`;
+    /// everything else goes through `Memory::read`.
+    fn load(&mut self, addr: u16) -> u16 {
+        if addr == devices::PROCESSOR_STATUS_ADDR {
+            self.registers.psr
+        } else {
+            self.memory.read(addr)
+        }
+    }
+
+    /// Load the pointer of an LDI/STI. This is a plain load: a pointer kept
+    /// in the device page is read without polling the device, and only the
+    /// access through the pointer behaves like a device access.
+    fn load_pointer(&mut self, addr: u16) -> u16 {
+        if addr == devices::PROCESSOR_STATUS_ADDR {
+            self.registers.psr
+        } else {
+            self.memory.read_plain(addr)
+        }
+    }
+
+    /// Store a word on behalf of an instruction. Writing the PSR's address
+    /// sets `Registers::psr`.
+    fn store(&mut self, addr: u16, val: u16) {
+        if addr == devices::PROCESSOR_STATUS_ADDR {
+            self.registers.psr = val;
+        } else {
+            self.memory.store(addr, val);
+        }
+    }
 }
 
 
 fn read_image(memory: &mut memory::Memory, mut image: impl Read) -> std::io::Result<u16> {
+    let invalid = |err: LoadError| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
     let mut buf = [0u8; 2];
 
     /* the origin tells us where in memory to place the image */
     let addr: u16 = {
-        image.read_exact(&mut buf)?;
+        if let Err(err) = image.read_exact(&mut buf) {
+            return Err(match err.kind() {
+                std::io::ErrorKind::UnexpectedEof => invalid(LoadError::MissingOrigin),
+                _ => err,
+            });
+        }
         u16::from_be_bytes(buf)
     };
 
-    let max_offset = (memory::MEMORY_SIZE - (addr as usize)) as u16;
-    for offset in 0..max_offset {
+    /* an image at x0000 may fill all of memory, which overflows a u16 count */
+    let max_offset = memory::MEMORY_SIZE - (addr as usize);
+    let mut words = Vec::new();
+    loop {
         if let Err(err) = image.read_exact(&mut buf) {
             match err.kind() {
                 std::io::ErrorKind::UnexpectedEof => break,
                 _ => return Err(err)
             }
         };
-        memory.write(addr + offset, u16::from_be_bytes(buf));
+        if words.len() == max_offset {
+            /* rather than silently dropping the rest of the program */
+            let mut rest = Vec::new();
+            image.read_to_end(&mut rest)?;
+            let word_count = max_offset + 1 + rest.len() / 2;
+            return Err(invalid(LoadError::Overflow { origin: addr, word_count }));
+        }
+        words.push(u16::from_be_bytes(buf));
+    }
+
+    /* only touch memory once the whole image is known to fit */
+    for (offset, word) in words.into_iter().enumerate() {
+        memory.write(addr + offset as u16, word);
     }
 
     Ok(addr)
@@ -380,3 +1148,675 @@ fn sign_extend(orig: u16, bit_count: u8) -> u16 {
     }
     x
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A VM with `source` assembled and loaded, no input, output discarded
+    /// and no diagnostics.
+    pub(crate) fn load(source: &str) -> VM {
+        let mut vm = VM::default();
+        vm.set_io(Box::new(BufferIo::default()));
+        vm.set_diagnostics(Diagnostics::Silent);
+        vm.load_bytes(&assemble(source).unwrap()).unwrap();
+        vm
+    }
+
+    /// Install an empty `BufferIo` on `vm`, returning a handle to queue
+    /// input and inspect the output.
+    pub(crate) fn capture_io(vm: &mut VM) -> Rc<RefCell<BufferIo>> {
+        let io = Rc::new(RefCell::new(BufferIo::default()));
+        vm.set_io(Box::new(io.clone()));
+        io
+    }
+
+    /// Collect `vm`'s diagnostic messages instead of printing them.
+    pub(crate) fn capture_diagnostics(vm: &mut VM) -> Rc<RefCell<Vec<String>>> {
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let sink = messages.clone();
+        vm.set_diagnostics(Diagnostics::Custom(Box::new(move |message| {
+            sink.borrow_mut().push(message.to_string())
+        })));
+        messages
+    }
+
+    #[test]
+    fn ddr_writes_reach_the_io_device() {
+        let mut vm = load(
+            ".ORIG x3000\nLEA R1, TEXT\n\
+             NEXT LDR R0, R1, #0\nBRz DONE\n\
+             WAIT LDI R2, DSR\nBRzp WAIT\nSTI R0, DDR\nADD R1, R1, #1\nBR NEXT\n\
+             DONE HALT\nDSR .FILL xFE04\nDDR .FILL xFE06\nTEXT .STRINGZ \"ok\"\n.END",
+        );
+        let io = capture_io(&mut vm);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"ok");
+        assert_eq!(vm.peek(devices::DISPLAY_DATA_ADDR), b'k' as u16);
+    }
+
+    #[test]
+    fn pc_offset9_boundaries_sign_extend() {
+        assert_eq!(sign_extend(0x1FF, 9) as i16, -1);
+        assert_eq!(sign_extend(0x100, 9) as i16, -256);
+        assert_eq!(sign_extend(0x0FF, 9) as i16, 255);
+
+        /* BRnzp #-1 branches to itself */
+        let mut vm = load(".ORIG x3000\n.FILL x0FFF\n.END");
+        vm.step();
+        assert_eq!(vm.pc(), 0x3000);
+
+        /* LD R0, #-256 and LD R1, #255, relative to the incremented PC */
+        let mut vm = load(".ORIG x3000\n.FILL x2100\n.FILL x22FF\nHALT\n.END");
+        vm.poke(0x2F01, 0x1234);
+        vm.poke(0x3101, 0x5678);
+        vm.step();
+        vm.step();
+        assert_eq!(vm.register(0), 0x1234);
+        assert_eq!(vm.register(1), 0x5678);
+    }
+
+    #[test]
+    fn run_slice_multiplexes_two_vms() {
+        let counter = ".ORIG x3000\nLOOP ADD R0, R0, #1\nBR LOOP\n.END";
+        let mut vms = [load(counter), load(counter)];
+        for round in 1..=3 {
+            for vm in vms.iter_mut() {
+                assert_eq!(vm.run_slice(10), (STATUS::Continue, 10));
+            }
+            for vm in &vms {
+                assert_eq!(vm.register(0), round * 5);
+            }
+        }
+    }
+
+    #[test]
+    fn current_disasm_follows_the_pc() {
+        let mut vm = load(".ORIG x3000\nADD R2, R0, #5\nAND R1, R1, #0\n.END");
+        assert_eq!(vm.current_disasm(), "ADD R2, R0, #5");
+        vm.step();
+        assert_eq!(vm.current_disasm(), "AND R1, R1, #0");
+    }
+
+    #[test]
+    fn stack_guard_catches_underflow() {
+        let mut vm = load(".ORIG x3000\nPOP ADD R6, R6, #1\nBR POP\n.END");
+        vm.set_register(6, 0x3FFE);
+        vm.set_stack_guard(Some(StackGuard { base: 0x4000, limit: 0x3F00 }));
+        assert_eq!(vm.run(), STATUS::StackUnderflow { sp: 0x4001 });
+    }
+
+    #[test]
+    fn device_page_reflects_poked_registers() {
+        let mut vm = load(".ORIG x3000\nHALT\n.END");
+        vm.poke(devices::KEYBOARD_DATA_ADDR, 0x41);
+        vm.poke(devices::PROCESSOR_STATUS_ADDR, 0x8004);
+        let page = vm.device_page();
+        let offset = |addr: u16| addr as usize - DEVICE_PAGE_ADDR;
+        assert_eq!(page[offset(devices::KEYBOARD_DATA_ADDR)], 0x41);
+        assert_eq!(page[offset(devices::PROCESSOR_STATUS_ADDR)], 0x8004);
+        assert_eq!(page[offset(devices::MACHINE_CONTROL_ADDR)], devices::CLOCK_ENABLE);
+    }
+
+    #[test]
+    fn disabled_keyboard_leaves_input_alone() {
+        let mut vm = load(".ORIG x3000\nLDI R0, KBSR\nHALT\nKBSR .FILL xFE00\n.END");
+        vm.set_io(Box::new(BufferIo::new(b"a")));
+        vm.set_keyboard_enabled(false);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(0), 0);
+        assert_eq!(vm.memory.get_char(), b'a');
+    }
+
+    #[test]
+    fn step_decoded_reports_the_executed_instructions() {
+        let mut vm = load(".ORIG x3000\nADD R2, R0, #5\nLD R1, DATA\nHALT\nDATA .FILL 7\n.END");
+        let (status, instr) = vm.step_decoded();
+        assert_eq!((status, instr), (STATUS::Continue, Some(Instruction::decode(0x1425))));
+        let (status, instr) = vm.step_decoded();
+        assert_eq!((status, instr), (STATUS::Continue, Some(Instruction::decode(0x2201))));
+        assert_eq!(vm.register(1), 7);
+    }
+
+    #[test]
+    fn step_decoded_reports_the_service_routine_after_an_interrupt() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nADD R0, R0, #1\nADD R0, R0, #1\n.END");
+        vm.poke(0x0181, 0x4000);
+        vm.poke(0x4000, 0x5260); // AND R1, R1, #0
+        vm.timer_interval = Some(2);
+        vm.step_decoded();
+        vm.step_decoded();
+        let (_, instr) = vm.step_decoded();
+        assert_eq!(instr, Some(Instruction::decode(0x5260)));
+        assert_eq!(vm.pc(), 0x4001);
+    }
+
+    #[test]
+    fn halt_message_goes_to_the_diagnostics() {
+        let mut vm = load(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"out\"\n.END");
+        let io = capture_io(&mut vm);
+        let messages = capture_diagnostics(&mut vm);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"out");
+        assert_eq!(*messages.borrow(), ["HALT"]);
+    }
+
+    #[test]
+    fn clear_halt_resumes_after_a_halt() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nHALT\nADD R0, R0, #1\nHALT\n.END");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert!(vm.is_halted());
+        assert_eq!(vm.halt_reason(), Some(STATUS::Halted));
+        assert_eq!(vm.register(0), 1);
+
+        vm.clear_halt();
+        assert!(!vm.is_halted());
+        assert_eq!(vm.halt_reason(), None);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(0), 2);
+        assert_eq!(vm.pc(), 0x3004);
+    }
+
+    #[test]
+    fn input_wait_hook_fires_when_starved() {
+        use std::{cell::Cell, rc::Rc};
+        let mut vm = load(".ORIG x3000\nGETC\nHALT\n.END");
+        let waits = Rc::new(Cell::new(0));
+        let counter = waits.clone();
+        vm.set_input_wait_hook(Box::new(move || counter.set(counter.get() + 1)));
+        assert_eq!(vm.step(), STATUS::HardInterrupt);
+        assert_eq!(waits.get(), 1);
+        assert_eq!(vm.pc(), 0x3000);
+    }
+
+    #[cfg(target_family = "wasm")]
+    #[test]
+    fn wasm_yields_after_the_poll_threshold() {
+        let mut vm = load(".ORIG x3000\nLOOP LDI R0, KBSR\nBRzp LOOP\nKBSR .FILL xFE00\n.END");
+        vm.set_poll_yield_threshold(3);
+        assert_eq!(vm.run_until(), STATUS::SoftInterrupt);
+        assert_eq!(vm.keyboard_polls(), 3);
+        assert_eq!(vm.run_until(), STATUS::SoftInterrupt);
+        assert_eq!(vm.keyboard_polls(), 6);
+    }
+
+    #[test]
+    fn wild_jump_guard_fires_in_user_mode_only() {
+        let mut vm = load(".ORIG x3000\nJMP R0\n.END");
+        vm.set_wild_jump_guard(true);
+        assert_eq!(vm.step(), STATUS::WildJump { target: 0 });
+
+        let mut vm = load(".ORIG x3000\nJMP R0\n.END");
+        vm.set_wild_jump_guard(true);
+        vm.poke(devices::PROCESSOR_STATUS_ADDR, 0x0002);
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!(vm.pc(), 0);
+    }
+
+    #[test]
+    fn psr_writes_set_the_condition_codes() {
+        /* loading x8001 sets N, storing it to the PSR makes it P */
+        let mut vm = load(
+            ".ORIG x3000\nLD R0, NEW\nSTI R0, PSR\nBRn NEG\nADD R1, R1, #1\nHALT\n\
+             NEG ADD R1, R1, #2\nHALT\nNEW .FILL x8001\nPSR .FILL xFFFC\n.END",
+        );
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(1), 1);
+
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #-1\nLDI R1, PSR\nHALT\nPSR .FILL xFFFC\n.END");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(1), 0x8004);
+    }
+
+    #[test]
+    fn every_nibble_converts_to_an_op() {
+        for nibble in 0..16u16 {
+            assert_eq!(OP::try_from(nibble).ok().map(|op| op as u16), Some(nibble));
+            assert!(OP::from_u16(nibble) == OP::try_from(nibble).ok());
+            assert_eq!(opcode(nibble << 12 | 0x0ABC) as u16, nibble);
+        }
+        assert!(OP::try_from(16).is_err());
+    }
+
+    #[test]
+    fn clearing_the_mcr_halts_after_the_store() {
+        let mut vm = load(
+            ".ORIG x3000\nAND R0, R0, #0\nSTI R0, MCR\nADD R1, R1, #1\nHALT\nMCR .FILL xFFFE\n.END",
+        );
+        assert_eq!(vm.step(), STATUS::Continue);
+        /* the store itself completes, then the machine stops */
+        assert_eq!(vm.step(), STATUS::Halted);
+        assert_eq!(vm.pc(), 0x3002);
+        assert_eq!(vm.step(), STATUS::Halted);
+        assert_eq!(vm.pc(), 0x3002);
+        assert_eq!(vm.register(1), 0);
+    }
+
+    #[test]
+    fn non_interactive_runs_compute_the_same() {
+        let source = ".ORIG x3000\nLEA R1, DATA\nAND R2, R2, #0\nADD R3, R2, #4\n\
+                      LOOP LDR R4, R1, #0\nADD R2, R2, R4\nSTR R2, R1, #0\nADD R1, R1, #1\n\
+                      ADD R3, R3, #-1\nBRp LOOP\nHALT\nDATA .FILL 3\n.FILL -7\n.FILL 12\n.FILL 40\n.END";
+        let image = assemble(source).unwrap();
+        let run = |mut vm: VM| {
+            vm.set_io(Box::new(BufferIo::default()));
+            vm.set_diagnostics(Diagnostics::Silent);
+            vm.load_bytes(&image).unwrap();
+            let status = vm.run();
+            let data: Vec<u16> = (0x300A..0x300E).map(|addr| vm.peek(addr)).collect();
+            (status, vm.registers.regs, vm.registers.psr, data)
+        };
+        let polling = run(VM::default());
+        assert_eq!(polling.3, [3, 0xFFFC, 8, 48]);
+        assert_eq!(run(VM::non_interactive()), polling);
+    }
+
+    #[test]
+    fn memory_moves_between_vms_with_its_devices() {
+        let mut first = load(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"moved\"\n.END");
+        let io = capture_io(&mut first);
+
+        let mut second = VM::default();
+        second.set_diagnostics(Diagnostics::Silent);
+        second.set_memory(first.take_memory());
+        second.set_pc(0x3000);
+        assert_eq!(first.peek(0x3000), 0);
+        assert_eq!(second.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"moved");
+        assert_eq!(second.register(0), 0x3003);
+    }
+
+    #[test]
+    fn return_guard_warns_about_a_bare_ret() {
+        let run = |source: &str, guard: bool| {
+            let mut vm = load(source);
+            let messages = capture_diagnostics(&mut vm);
+            vm.set_return_guard(guard);
+            vm.step();
+            vm.step();
+            messages.take()
+        };
+        let bare = ".ORIG x3000\nRET\n.END";
+        assert_eq!(run(bare, true), ["warning: RET to x0000, which is not a return address"]);
+        assert!(run(bare, false).is_empty());
+        assert!(run(".ORIG x3000\nJSR SUB\nHALT\nSUB RET\n.END", true).is_empty());
+    }
+
+    #[test]
+    fn relocated_code_runs_at_its_new_address() {
+        let mut vm = load(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"hi\"\n.END");
+        let io = capture_io(&mut vm);
+        vm.relocate(0x3000..0x3006, 0x4000);
+        assert_eq!(vm.pc(), 0x4000);
+        assert_eq!(vm.peek(0x3000), 0);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"hi");
+        assert_eq!(vm.register(0), 0x4003);
+    }
+
+    #[test]
+    fn repeated_kbsr_polls_keep_the_waiting_character() {
+        let mut vm = load(
+            ".ORIG x3000\nLDI R1, KBSR\nLDI R1, KBSR\nLDI R0, KBDR\nHALT\n\
+             KBSR .FILL xFE00\nKBDR .FILL xFE02\n.END",
+        );
+        vm.push_input(b"ab");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(0), b'a' as u16);
+    }
+
+    #[test]
+    fn polling_loop_reads_pushed_input_in_order() {
+        let mut vm = load(
+            ".ORIG x3000\nLEA R3, BUF\nAND R2, R2, #0\nADD R2, R2, #3\n\
+             POLL LDI R1, KBSR\nBRzp POLL\nLDI R0, KBDR\nSTR R0, R3, #0\nADD R3, R3, #1\n\
+             ADD R2, R2, #-1\nBRp POLL\nHALT\n\
+             KBSR .FILL xFE00\nKBDR .FILL xFE02\nBUF .BLKW 3\n.END",
+        );
+        vm.push_input(b"xyz");
+        assert_eq!(vm.run(), STATUS::Halted);
+        let buf: Vec<u16> = (0x300D..0x3010).map(|addr| vm.peek(addr)).collect();
+        assert_eq!(buf, [b'x' as u16, b'y' as u16, b'z' as u16]);
+    }
+
+    #[test]
+    fn typed_accessors_match_the_raw_ones() {
+        let mut vm = load(".ORIG x3000\nADD R3, R3, #9\nST R3, DATA\nHALT\nDATA .FILL 0\n.END");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.get(Reg::R3), 9);
+        assert_eq!(vm.get(Reg::R3), vm.register(3));
+        assert_eq!(vm.peek(Addr(0x3003)), 9);
+        vm.poke(Addr(0x3003), 4);
+        assert_eq!(vm.peek(0x3003), 4);
+    }
+
+    #[cfg(target_family = "wasm")]
+    #[test]
+    fn wasm_step_tells_a_reserved_op_from_a_halt() {
+        let mut vm = load(".ORIG x3000\n.FILL xD000\n.END");
+        assert_eq!(vm.step_wasm(), STATUS::Fault(ExecError::ReservedOp).code());
+        assert_eq!(vm.step_wasm(), 2);
+
+        let mut vm = load(".ORIG x3000\nHALT\n.END");
+        assert_eq!(vm.step_wasm(), 1);
+
+        let mut vm = load(".ORIG x3000\n.FILL xD000\nHALT\n.END");
+        vm.set_skip_illegal(true);
+        assert_eq!(vm.step_wasm(), 0);
+        assert_eq!(vm.step_wasm(), 1);
+    }
+
+    #[test]
+    fn status_codes_tell_a_fault_from_a_halt() {
+        assert_eq!(STATUS::Halted.code(), 1);
+        assert_eq!(STATUS::Fault(ExecError::ReservedOp).code(), 2);
+        assert_eq!(STATUS::Fault(ExecError::UnknownTrap(0x30)).code(), 2);
+    }
+
+    #[test]
+    fn run_returns_on_halt() {
+        use std::{sync::mpsc, thread, time::Duration};
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            let mut vm = load(".ORIG x3000\nHALT\n.END");
+            done.send(vm.run()).unwrap();
+        });
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(STATUS::Halted));
+    }
+
+    #[test]
+    fn set_register_updates_the_condition_codes() {
+        let mut vm = VM::default();
+        vm.set_register(0, 0x8000);
+        assert_eq!(vm.register(0), 0x8000);
+        assert_eq!(vm.condition(), 0b100);
+        vm.set_register(3, 0);
+        assert_eq!(vm.condition(), 0b010);
+        vm.set_register(7, 1);
+        assert_eq!(vm.condition(), 0b001);
+        vm.set_pc(0x4000);
+        assert_eq!(vm.pc(), 0x4000);
+    }
+
+    #[test]
+    fn forks_run_independently() {
+        let vm = load(".ORIG x3000\nADD R0, R0, R1\nST R0, RESULT\nHALT\nRESULT .FILL 0\n.END");
+        let mut a = vm.fork();
+        let mut b = vm.fork();
+        a.set_register(1, 5);
+        b.set_register(1, 9);
+        assert_eq!(a.run(), STATUS::Halted);
+        assert_eq!(b.run(), STATUS::Halted);
+        assert_eq!((a.register(0), a.peek(0x3003)), (5, 5));
+        assert_eq!((b.register(0), b.peek(0x3003)), (9, 9));
+        assert_eq!((vm.register(0), vm.peek(0x3003), vm.pc()), (0, 0, 0x3000));
+    }
+
+    #[test]
+    fn peeking_at_kbsr_leaves_the_input_alone() {
+        let mut vm = load(".ORIG x3000\nHALT\n.END");
+        vm.set_io(Box::new(BufferIo::new(b"a")));
+        assert_eq!(vm.peek(devices::KEYBOARD_STATUS_ADDR), 0);
+        assert_eq!(vm.peek(devices::KEYBOARD_STATUS_ADDR), 0);
+        assert_eq!(vm.memory.get_char(), b'a');
+
+        vm.poke(0x3100, 0x1234);
+        assert_eq!(vm.peek(0x3100), 0x1234);
+    }
+
+    #[test]
+    fn slow_display_is_busy_after_each_character() {
+        let mut vm = load(
+            ".ORIG x3000\nLD R0, CHAR\nSTI R0, DDR\nLDI R1, DSR\nLDI R2, DSR\nLDI R3, DSR\nHALT\n\
+             CHAR .FILL x61\nDDR .FILL xFE06\nDSR .FILL xFE04\n.END",
+        );
+        let io = capture_io(&mut vm);
+        vm.set_display_delay(2);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"a");
+        assert_eq!((vm.register(1), vm.register(2), vm.register(3)), (0, 0, 0x8000));
+    }
+
+    #[test]
+    fn tee_output_reaches_both_sinks() {
+        struct Log(Rc<RefCell<Vec<u8>>>);
+        impl std::io::Write for Log {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vm = load(".ORIG x3000\nLD R0, CHAR\nOUT\nHALT\nCHAR .FILL x7A\n.END");
+        let io = capture_io(&mut vm);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        vm.tee_output(Box::new(Log(log.clone())));
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"z");
+        assert_eq!(*log.borrow(), b"z");
+    }
+
+    #[test]
+    fn resume_with_input_feeds_the_waiting_getc() {
+        let mut vm = load(".ORIG x3000\nGETC\nADD R1, R0, #0\nGETC\nADD R2, R0, #0\nHALT\n.END");
+        assert_eq!(vm.step(), STATUS::HardInterrupt);
+        assert_eq!(vm.pc(), 0x3000);
+        assert_eq!(vm.resume_with_input(b'a'), STATUS::HardInterrupt);
+        assert_eq!((vm.register(1), vm.pc()), (b'a' as u16, 0x3002));
+        assert_eq!(vm.resume_with_input(b'b'), STATUS::Halted);
+        assert_eq!(vm.register(2), b'b' as u16);
+    }
+
+    #[test]
+    fn keyboard_polls_count_each_kbsr_read() {
+        let mut vm = load(
+            ".ORIG x3000\nAND R2, R2, #0\nADD R2, R2, #5\nLOOP LDI R1, KBSR\nADD R2, R2, #-1\nBRp LOOP\nHALT\n\
+             KBSR .FILL xFE00\n.END",
+        );
+        assert_eq!(vm.keyboard_polls(), 0);
+        vm.step();
+        vm.step();
+        vm.step();
+        assert_eq!(vm.keyboard_polls(), 1);
+        vm.peek(devices::KEYBOARD_STATUS_ADDR);
+        assert_eq!(vm.keyboard_polls(), 1);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.keyboard_polls(), 5);
+    }
+
+    #[test]
+    fn fetch_preview_names_the_opcode_at_the_pc() {
+        let mut vm = load(".ORIG x3000\nADD R2, R0, #5\nLDR R1, R6, #-2\n.END");
+        assert_eq!(vm.fetch_preview(), (0x3000, 0x1425, "ADD"));
+        vm.step();
+        assert_eq!(vm.fetch_preview(), (0x3001, 0x63BE, "LDR"));
+        assert_eq!(vm.pc(), 0x3001);
+    }
+
+    #[test]
+    fn run_steps_stops_a_branch_to_itself() {
+        let mut vm = load(".ORIG x3000\nSELF BR SELF\n.END");
+        assert_eq!(vm.run_steps(100), (100, STATUS::StepLimitReached));
+        assert_eq!(vm.pc(), 0x3000);
+
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END");
+        assert_eq!(vm.run_steps(100), (2, STATUS::Halted));
+    }
+
+    #[test]
+    fn puts_goes_through_a_booted_os() {
+        /* just enough of an OS: PUTS and HALT routines in the trap table,
+           and boot code that drops to the user program at x3000 */
+        let os = assemble(
+            ".ORIG x0022
+             .FILL DO_PUTS
+             .FILL 0
+             .FILL 0
+             .FILL DO_HALT
+             .BLKW x1DA
+             BOOT    LD R6, STACK
+                     LD R0, USER_PSR
+                     ADD R6, R6, #-1
+                     STR R0, R6, #0
+                     LD R0, USER_PC
+                     ADD R6, R6, #-1
+                     STR R0, R6, #0
+                     RTI
+             DO_PUTS ST R1, SAVE_R1
+             NEXT    LDR R1, R0, #0
+                     BRz DONE
+             WAIT    LDI R2, DSR
+                     BRzp WAIT
+                     STI R1, DDR
+                     ADD R0, R0, #1
+                     BR NEXT
+             DONE    LD R1, SAVE_R1
+                     RET
+             DO_HALT AND R0, R0, #0
+                     STI R0, MCR
+             STACK   .FILL x2FF0
+             USER_PSR .FILL x8002
+             USER_PC .FILL x3000
+             SAVE_R1 .FILL 0
+             DSR     .FILL xFE04
+             DDR     .FILL xFE06
+             MCR     .FILL xFFFE
+             .END",
+        )
+        .unwrap();
+        let mut vm = load(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"via OS\"\n.END");
+        let io = capture_io(&mut vm);
+        vm.boot_os(&os).unwrap();
+        assert_eq!(vm.pc(), OS_ENTRY);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"via OS");
+        assert!(!vm.registers.privilege());
+    }
+
+    #[test]
+    fn counter_counts_executed_instructions() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nADD R0, R0, #1\nAND R1, R1, #0\nNOT R2, R1\nHALT\n.END");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.instructions_executed, 5);
+        vm.reset_counter();
+        assert_eq!(vm.instructions_executed, 0);
+
+        /* faulting instructions don't count */
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\n.FILL xD000\n.END");
+        assert_eq!(vm.run(), STATUS::Fault(ExecError::ReservedOp));
+        assert_eq!(vm.instructions_executed, 1);
+    }
+
+    #[test]
+    fn writing_zero_to_the_mcr_halts_the_next_step() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END");
+        assert_eq!(vm.memory.read(devices::MACHINE_CONTROL_ADDR) & 0x8000, 0x8000);
+        vm.memory.write(devices::MACHINE_CONTROL_ADDR, 0x0000);
+        assert_eq!(vm.memory.read(devices::MACHINE_CONTROL_ADDR), 0x0000);
+        assert_eq!(vm.step(), STATUS::Halted);
+        assert_eq!(vm.register(0), 0);
+    }
+
+    #[test]
+    fn two_images_load_side_by_side() {
+        let dir = std::env::temp_dir();
+        let os = dir.join(format!("lc3-os-{}.obj", std::process::id()));
+        let user = dir.join(format!("lc3-user-{}.obj", std::process::id()));
+        std::fs::write(&os, assemble(".ORIG x0200\n.FILL x1111\n.FILL x2222\n.END").unwrap()).unwrap();
+        std::fs::write(&user, assemble(".ORIG x3000\n.FILL x3333\n.END").unwrap()).unwrap();
+
+        let mut vm = VM::default();
+        assert_eq!(vm.load_file_at(user.to_str().unwrap(), true).unwrap(), 0x3000);
+        assert_eq!(vm.load_file_at(os.to_str().unwrap(), false).unwrap(), 0x0200);
+        std::fs::remove_file(&os).unwrap();
+        std::fs::remove_file(&user).unwrap();
+
+        assert_eq!(vm.pc(), 0x3000);
+        assert_eq!(vm.peek(0x0200), 0x1111);
+        assert_eq!(vm.peek(0x0201), 0x2222);
+        assert_eq!(vm.peek(0x3000), 0x3333);
+    }
+
+    #[test]
+    fn run_tells_a_halt_from_an_illegal_instruction() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END");
+        assert_eq!(vm.run(), STATUS::Halted);
+
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\n.FILL xD000\nHALT\n.END");
+        assert_eq!(vm.run(), STATUS::Fault(ExecError::ReservedOp));
+        assert_eq!(vm.register(0), 1);
+    }
+
+    #[test]
+    fn run_until_returns_when_getc_has_no_input() {
+        let mut vm = load(".ORIG x3000\nADD R1, R1, #1\nGETC\nOUT\nHALT\n.END");
+        let io = capture_io(&mut vm);
+
+        assert_eq!(vm.run_until(), STATUS::HardInterrupt);
+        assert_eq!(vm.pc(), 0x3001);
+        assert!(!vm.is_halted());
+        assert_eq!(vm.run_until(), STATUS::HardInterrupt);
+
+        io.borrow_mut().input.push_back(b'x');
+        assert_eq!(vm.run_until(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"x");
+    }
+
+    #[test]
+    fn loading_rejects_oversized_and_empty_images() {
+        let load_error =
+            |err: std::io::Error| err.into_inner().unwrap().downcast::<LoadError>().unwrap();
+        let mut vm = VM::default();
+
+        let err = vm.load_bytes(&[0xFF, 0xFE, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(*load_error(err), LoadError::Overflow { origin: 0xFFFE, word_count: 3 });
+        /* nothing of the rejected image was written */
+        assert_eq!(vm.peek(0xFFFE), devices::CLOCK_ENABLE);
+
+        let err = vm.load_bytes(&[]).unwrap_err();
+        assert_eq!(*load_error(err), LoadError::MissingOrigin);
+        assert_eq!(vm.pc(), 0x3000);
+    }
+
+    #[test]
+    fn loads_a_hand_built_image_from_a_reader() {
+        /* origin x4000, then ADD R0, R0, #7 and HALT */
+        let image: Vec<u8> = vec![0x40, 0x00, 0x10, 0x27, 0xF0, 0x25];
+        let mut vm = VM::default();
+        assert_eq!(vm.load_reader(std::io::Cursor::new(image.clone())).unwrap(), 0x4000);
+        assert_eq!(vm.pc(), 0x4000);
+        assert_eq!((vm.peek(0x4000), vm.peek(0x4001)), (0x1027, 0xF025));
+
+        let mut vm = VM::default();
+        vm.set_diagnostics(Diagnostics::Silent);
+        assert_eq!(vm.load_bytes(&image).unwrap(), 0x4000);
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!(vm.register(0), 7);
+    }
+
+    #[test]
+    fn mcr_and_psr_reads_show_the_live_state() {
+        let mut vm = load(
+            ".ORIG x3000\nLDI R0, MCR\nLDI R1, PSR\nHALT\nMCR .FILL xFFFE\nPSR .FILL xFFFC\n.END",
+        );
+        vm.step();
+        assert_eq!(vm.register(0), 0x8000);
+        vm.step();
+        /* user mode, priority 0, and N from loading the MCR's 0x8000 */
+        assert_eq!(vm.register(1), 0x8004);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.memory.read(devices::MACHINE_CONTROL_ADDR) & 0x8000, 0);
+    }
+
+    #[test]
+    fn run_slice_keeps_the_halt_reason_and_skips_faults() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\n.FILL xD000\n.END");
+        assert_eq!(vm.run_slice(10), (STATUS::Fault(ExecError::ReservedOp), 1));
+        assert_eq!(vm.instructions_executed, 1);
+        assert_eq!(vm.run_slice(10), (STATUS::Fault(ExecError::ReservedOp), 0));
+    }
+}
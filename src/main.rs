@@ -1,35 +1,128 @@
+use std::cell::Cell;
 use std::env;
 use std::process::exit;
+use std::rc::Rc;
 
 use termios::*;
 
-use lc3::VM;
+use lc3::{Diagnostics, Io, TerminalIo, STATUS, VM};
 
 
+/// The terminal, remembering the last byte written to it.
+struct Console {
+    terminal: TerminalIo,
+    last_output: Rc<Cell<u8>>,
+}
+
+impl Io for Console {
+    fn get_char(&mut self) -> u8 {
+        self.terminal.get_char()
+    }
+
+    fn put_char(&mut self, c: u8) {
+        self.last_output.set(c);
+        self.terminal.put_char(c)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.terminal.at_eof()
+    }
+}
+
 fn main() {
     let mut vm = VM::default();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("lc3 [image-file1] ...");
+    let (options, images): (Vec<&String>, Vec<&String>) =
+        args.iter().skip(1).partition(|arg| arg.starts_with("--"));
+    if images.is_empty() {
+        println!("lc3 [--newline-on-halt] [--eof-halt] [--line-input] [image-file1] ...  (use - to read an image from stdin)");
         exit(2);
     }
 
-    args.iter().skip(1).for_each(|image| {
-        if vm.load_file(image).is_err() {
+    let mut line_input = false;
+    for option in options {
+        match option.as_str() {
+            // Start the HALT message on a fresh line if the program
+            // stopped partway through one.
+            "--newline-on-halt" => {
+                let last_output = Rc::new(Cell::new(b'\n'));
+                vm.set_io(Box::new(Console {
+                    terminal: TerminalIo::default(),
+                    last_output: last_output.clone(),
+                }));
+                vm.set_diagnostics(Diagnostics::Custom(Box::new(move |message| {
+                    if message.starts_with("HALT") && last_output.get() != b'\n' {
+                        eprintln!();
+                    }
+                    eprintln!("{}", message);
+                })));
+            }
+            // Stop cleanly once stdin is exhausted (e.g. a finite pipe)
+            // rather than waiting for more input forever.
+            "--eof-halt" => vm.set_eof_halt(true),
+            // Give the program a whole line at a time, with backspace
+            // editing, instead of each keystroke as it's typed.
+            "--line-input" => line_input = true,
+            _ => {
+                println!("unknown option: {}", option);
+                exit(2);
+            }
+        }
+    }
+    // Wrap whichever device the other options installed.
+    if line_input {
+        vm.line_buffer_input(true);
+    }
+
+    // The first image is the program to run; any others (e.g. an OS or a
+    // library) only fill in memory and leave the PC alone.
+    images.iter().enumerate().for_each(|(index, image)| {
+        let set_pc = index == 0;
+        let result = if image.as_str() == "-" {
+            vm.load_reader_at(std::io::stdin().lock(), set_pc)
+        } else {
+            vm.load_file_at(image, set_pc)
+        };
+        if result.is_err() {
             println!("failed to load image: {}", image);
             exit(1);
         }
     });
 
     // Get the terminal working such that it reads one char at a time.
+    // stdin may not be a terminal, e.g. when the image was piped in.
     let stdin = 0;
-    let mut termios = Termios::from_fd(stdin).unwrap();
-    termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
-    termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
-    tcsetattr(stdin, TCSANOW, &mut termios).unwrap();
+    let termios = Termios::from_fd(stdin).ok();
+    if let Some(mut termios) = termios {
+        termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
+        termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
+        tcsetattr(stdin, TCSANOW, &termios).unwrap();
+    }
 
-    vm.run();
+    let status = vm.run();
 
-    tcsetattr(stdin, TCSANOW, &termios).unwrap();
-}
\ No newline at end of file
+    if let Some(termios) = termios {
+        tcsetattr(stdin, TCSANOW, &termios).unwrap();
+    }
+    // Drop the VM first so that buffered output is flushed, since `exit`
+    // doesn't run destructors.
+    drop(vm);
+    exit(exit_code(status));
+}
+
+/// The exit code for how the program stopped, so that scripts can detect
+/// failures. 1 and 2 are taken by load and usage errors.
+fn exit_code(status: STATUS) -> i32 {
+    match status {
+        STATUS::Halted => 0,
+        STATUS::Fault(_) => 3,
+        STATUS::StackUnderflow { .. }
+        | STATUS::StackOverflow { .. }
+        | STATUS::WildJump { .. }
+        | STATUS::AddressWrap { .. }
+        | STATUS::TrapTableWrite { .. } => 4,
+        STATUS::StepLimitReached | STATUS::OutputLimitReached => 5,
+        STATUS::Continue | STATUS::SoftInterrupt | STATUS::HardInterrupt => 0,
+    }
+}
@@ -0,0 +1,86 @@
+//! Presetting memory from a plain-text map of `addr = value` lines, which
+//! keeps test fixtures readable:
+//!
+//! ```text
+//! ; the operand for the routine at x3000
+//! x3100 = #42
+//! x3101 = xFFFF
+//! ```
+//!
+//! Numbers use the assembler's syntax (`#10`, `#-3`, `x3000`, `0x3000` or a
+//! bare decimal number), and `;` starts a comment.
+
+use std::fmt;
+
+use crate::assembler::parse_number;
+use crate::VM;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// The 1-based line the error was found on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl VM {
+    /// Write every `addr = value` pair in `spec` to memory. Nothing is
+    /// written unless the whole map parses.
+    pub fn load_memory_map(&mut self, spec: &str) -> Result<(), ParseError> {
+        let mut pokes = Vec::new();
+        for (index, text) in spec.lines().enumerate() {
+            let line = index + 1;
+            let err = |message: String| ParseError { line, message };
+            let text = text.split(';').next().unwrap_or("").trim();
+            if text.is_empty() {
+                continue;
+            }
+            let (addr, value) = text
+                .split_once('=')
+                .ok_or_else(|| err(format!("expected 'addr = value', found '{}'", text)))?;
+            let addr = match parse_number(addr.trim()) {
+                Some(addr @ 0..=0xFFFF) => addr as u16,
+                _ => return Err(err(format!("bad address '{}'", addr.trim()))),
+            };
+            let value = match parse_number(value.trim()) {
+                Some(value @ -0x8000..=0xFFFF) => value as u16,
+                _ => return Err(err(format!("bad value '{}'", value.trim()))),
+            };
+            pokes.push((addr, value));
+        }
+        for (addr, value) in pokes {
+            self.memory.write(addr, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_a_small_map() {
+        let mut vm = VM::default();
+        let spec = "; operands\nx3100 = #42\n0x3101 = xFFFF\n\n12288 = #-3 ; x3000\n";
+        assert_eq!(vm.load_memory_map(spec), Ok(()));
+        assert_eq!(vm.peek(0x3100), 42);
+        assert_eq!(vm.peek(0x3101), 0xFFFF);
+        assert_eq!(vm.peek(0x3000), 0xFFFD);
+    }
+
+    #[test]
+    fn a_bad_line_writes_nothing() {
+        let mut vm = VM::default();
+        let err = vm.load_memory_map("x3100 = #1\nx3101 #2\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(vm.peek(0x3100), 0);
+    }
+}
@@ -1,44 +1,282 @@
+use crate::devices::{Devices, KEYBOARD_CHECK_ADDR};
+use crate::io::Io;
+
 pub const MEMORY_SIZE: usize = 1 << 16;
-const KEYBOARD_STATUS_ADDR: usize = 0xFE00;
-const KEYBOARD_DATA_ADDR: usize   = 0xFE02;
 
-/// KEYBOARD_CHECK_ADDR is an address I am custom defining.
-/// It records if the program has checked the KEYBOARD_STATUS
-/// address. This is useful for the WASM code to determine
-/// when to suspend execution to await user input.
-const KEYBOARD_CHECK_ADDR: usize = 0xFE04;
+/// The memory-mapped device registers (KBSR, KBDR, DSR, DDR, PSR, MCR)
+/// all live in the top page of the address space, 0xFE00 - 0xFFFF.
+pub const DEVICE_PAGE_ADDR: usize = 0xFE00;
+pub const DEVICE_PAGE_SIZE: usize = MEMORY_SIZE - DEVICE_PAGE_ADDR;
 
-pub struct Memory([u16; MEMORY_SIZE]);
+/// The 64K words of LC-3 memory along with the devices mapped into it.
+pub struct Memory {
+    cells: [u16; MEMORY_SIZE],
+    /// The address and previous value of the most recent write.
+    last_write: Option<(u16, u16)>,
+    /// While enabled, the address and previous value of every write.
+    write_log: Option<Vec<(u16, u16)>>,
+    devices: Devices,
+    /// How program reads are done, fixed when the memory is created (see
+    /// `Memory::non_interactive`).
+    reader: fn(&mut Memory, u16) -> u16,
+    /// Addresses whose next access faults (see `VM::inject_memory_fault_at`).
+    #[cfg(debug_assertions)]
+    faults: std::collections::BTreeSet<u16>,
+    /// The injected fault tripped since the last check.
+    #[cfg(debug_assertions)]
+    tripped_fault: Option<u16>,
+}
 
 impl Default for Memory {
     fn default() -> Self {
-        Memory([0; MEMORY_SIZE])
+        Memory {
+            cells: [0; MEMORY_SIZE],
+            last_write: None,
+            write_log: None,
+            devices: Devices::default(),
+            reader: Memory::read_devices,
+            #[cfg(debug_assertions)]
+            faults: Default::default(),
+            #[cfg(debug_assertions)]
+            tripped_fault: None,
+        }
     }
 }
 
 impl Memory {
+    /// Memory for programs that never take input. Program reads have no
+    /// device side effects, so the keyboard is never polled, and it can't
+    /// interrupt either. GETC and IN still read from the I/O device.
+    pub(crate) fn non_interactive() -> Self {
+        let mut memory = Memory { reader: Memory::read_cells, ..Memory::default() };
+        memory.set_keyboard_enabled(false);
+        memory
+    }
+
     pub fn read(&mut self, addr: u16) -> u16 {
-        if addr == KEYBOARD_STATUS_ADDR as u16 {
-            self.0[KEYBOARD_CHECK_ADDR] = 1;
-            let c = super::io::get_char();
-            if c != 0 {
-                self.0[KEYBOARD_STATUS_ADDR] = 1 << 15;
-                self.0[KEYBOARD_DATA_ADDR] = c as u16;
-            } else {
-                self.0[KEYBOARD_STATUS_ADDR] = 0;
+        #[cfg(debug_assertions)]
+        if self.check_fault(addr) {
+            return 0;
+        }
+        (self.reader)(self, addr)
+    }
+
+    /// Read a word, with the side effects of reading a device register.
+    fn read_devices(&mut self, addr: u16) -> u16 {
+        if Devices::owns(addr) {
+            self.devices.read(addr)
+        } else {
+            self.devices.clear_keyboard_check();
+            self.cells[addr as usize]
+        }
+    }
+
+    /// Read a word as `peek` does, for `Memory::non_interactive`.
+    fn read_cells(&mut self, addr: u16) -> u16 {
+        self.peek(addr)
+    }
+
+    /// Read a word on behalf of the program, but without any device side
+    /// effects if `addr` is a device register. Used for values that are
+    /// only ever treated as data, such as the pointer of an LDI/STI.
+    pub(crate) fn read_plain(&mut self, addr: u16) -> u16 {
+        if Devices::owns(addr) {
+            #[cfg(debug_assertions)]
+            if self.check_fault(addr) {
+                return 0;
             }
+            self.devices.peek(addr)
+        } else {
+            self.read(addr)
+        }
+    }
+
+    /// A copy of memory and the device state, with the default terminal as
+    /// its I/O device.
+    pub(crate) fn fork(&self) -> Memory {
+        Memory {
+            cells: self.cells,
+            last_write: None,
+            write_log: None,
+            devices: self.devices.fork(),
+            reader: self.reader,
+            #[cfg(debug_assertions)]
+            faults: self.faults.clone(),
+            #[cfg(debug_assertions)]
+            tripped_fault: None,
+        }
+    }
+
+    pub(crate) fn set_display_delay(&mut self, polls: u32) {
+        self.devices.set_display_delay(polls);
+    }
+
+    pub(crate) fn set_keyboard_enabled(&mut self, enabled: bool) {
+        self.devices.set_keyboard_enabled(enabled);
+    }
+
+    pub(crate) fn keyboard_enabled(&self) -> bool {
+        self.devices.keyboard_enabled()
+    }
+
+    pub(crate) fn io(&mut self) -> &mut dyn Io {
+        self.devices.io()
+    }
+
+    /// The next input character, taking queued input before the device.
+    pub(crate) fn get_char(&mut self) -> u8 {
+        self.devices.get_char()
+    }
+
+    pub(crate) fn keyboard_interrupt(&mut self) -> bool {
+        self.devices.keyboard_interrupt()
+    }
+
+    pub(crate) fn push_input(&mut self, input: &[u8]) {
+        self.devices.push_input(input);
+    }
+
+    /// Replace the character device, returning the previous one.
+    pub(crate) fn set_io(&mut self, io: Box<dyn Io>) -> Box<dyn Io> {
+        self.devices.set_io(io)
+    }
+
+    /// Read a word without triggering any device side effects.
+    pub fn peek(&self, addr: u16) -> u16 {
+        if Devices::owns(addr) {
+            self.devices.peek(addr)
         } else {
-            self.0[KEYBOARD_CHECK_ADDR] = 0;
+            self.cells[addr as usize]
+        }
+    }
+
+    /// Copy out the device register page without any side effects.
+    pub(crate) fn device_page(&self) -> [u16; DEVICE_PAGE_SIZE] {
+        let mut page = [0; DEVICE_PAGE_SIZE];
+        for (offset, word) in page.iter_mut().enumerate() {
+            *word = self.peek((DEVICE_PAGE_ADDR + offset) as u16);
         }
-        self.0[addr as usize]
+        page
     }
 
+    /// Write a word on behalf of a program. Unlike `write`, this triggers
+    /// device side effects, e.g. a write to the DDR displays a character.
+    pub fn store(&mut self, addr: u16, val: u16) {
+        #[cfg(debug_assertions)]
+        if self.check_fault(addr) {
+            return;
+        }
+        if Devices::owns(addr) {
+            self.record_write(addr);
+            self.devices.store(addr, val);
+        } else {
+            self.write(addr, val);
+        }
+    }
+
+    /// Write a word without triggering any device side effects.
     pub fn write(&mut self, addr: u16, val: u16) {
-        self.0[addr as usize] = val;
+        self.record_write(addr);
+        if Devices::owns(addr) {
+            self.devices.write(addr, val);
+        } else {
+            self.cells[addr as usize] = val;
+        }
+    }
+
+    fn record_write(&mut self, addr: u16) {
+        let old = self.peek(addr);
+        self.last_write = Some((addr, old));
+        if let Some(log) = &mut self.write_log {
+            log.push((addr, old));
+        }
+    }
+
+    /// Start logging every write, until `take_write_log`.
+    pub(crate) fn start_write_log(&mut self) {
+        self.write_log = Some(Vec::new());
+    }
+
+    /// Stop logging writes, returning the address and previous value of
+    /// each one in order.
+    pub(crate) fn take_write_log(&mut self) -> Vec<(u16, u16)> {
+        self.write_log.take().unwrap_or_default()
+    }
+
+    pub(crate) fn last_write(&self) -> Option<(u16, u16)> {
+        self.last_write
+    }
+
+    pub(crate) fn clear_last_write(&mut self) {
+        self.last_write = None;
+    }
+
+    #[cfg(debug_assertions)]
+    pub(crate) fn inject_fault(&mut self, addr: u16) {
+        self.faults.insert(addr);
+    }
+
+    /// Whether an access to `addr` must be refused: it is the first access
+    /// to an address set up to fault, or a fault already tripped during
+    /// this instruction. A refused read returns 0 and a refused store is
+    /// dropped.
+    #[cfg(debug_assertions)]
+    fn check_fault(&mut self, addr: u16) -> bool {
+        if self.faults.remove(&addr) {
+            self.tripped_fault.get_or_insert(addr);
+        }
+        self.tripped_fault.is_some()
+    }
+
+    #[cfg(debug_assertions)]
+    pub(crate) fn has_faults(&self) -> bool {
+        !self.faults.is_empty()
+    }
+
+    /// The address of an injected fault tripped since the last call.
+    #[cfg(debug_assertions)]
+    pub(crate) fn take_tripped_fault(&mut self) -> Option<u16> {
+        self.tripped_fault.take()
+    }
+
+    /// Whether a KBSR poll found no input since the last call.
+    pub(crate) fn take_input_starved(&mut self) -> bool {
+        self.devices.take_input_starved()
+    }
+
+    pub(crate) fn keyboard_polls(&self) -> u64 {
+        self.devices.keyboard_polls()
     }
 
     #[allow(dead_code)]
-    pub fn kbstatus(&self) -> u16 {
-        self.0[KEYBOARD_CHECK_ADDR]
+    pub(crate) fn kbstatus(&self) -> u16 {
+        self.devices.peek(KEYBOARD_CHECK_ADDR)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static READS: RefCell<Vec<u16>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn logging_reader(memory: &mut Memory, addr: u16) -> u16 {
+        READS.with(|reads| reads.borrow_mut().push(addr));
+        memory.read_devices(addr)
+    }
+
+    #[test]
+    fn puts_reads_each_word_once() {
+        let mut vm = load(".ORIG x3000\nLEA R0, TEXT\nPUTS\nHALT\nTEXT .STRINGZ \"hi\"\n.END");
+        vm.memory.reader = logging_reader;
+        vm.step();
+        READS.with(|reads| reads.borrow_mut().clear());
+        vm.step();
+        let reads = READS.with(|reads| reads.take());
+        let text_reads: Vec<u16> = reads.into_iter().filter(|&addr| addr >= 0x3003).collect();
+        assert_eq!(text_reads, [0x3003, 0x3004, 0x3005]);
+    }
+}
@@ -0,0 +1,637 @@
+//! The instruction handlers. Each opcode has its own method, and
+//! `execute` dispatches on the top four bits of the instruction through
+//! `DISPATCH` rather than decoding an `OP` first.
+//!
+//! A handler returns `STATUS::Continue` once the instruction has completed
+//! normally, or the status that stops this step early.
+
+use num_traits::FromPrimitive;
+
+use crate::{interrupt, sign_extend, ExecError, STATUS, TRAP, VM};
+
+/// The handler for each opcode, indexed by `instr >> 12`.
+pub(crate) const DISPATCH: [fn(&mut VM, u16) -> STATUS; 16] = [
+    VM::op_br,
+    VM::op_add,
+    VM::op_ld,
+    VM::op_st,
+    VM::op_jsr,
+    VM::op_and,
+    VM::op_ldr,
+    VM::op_str,
+    VM::op_rti,
+    VM::op_not,
+    VM::op_ldi,
+    VM::op_sti,
+    VM::op_jmp,
+    VM::op_res,
+    VM::op_lea,
+    VM::op_trap,
+];
+
+impl VM {
+    fn op_add(&mut self, instr: u16) -> STATUS {
+        /* |0001| DR|SR1|0|00|SR2|
+           |0001| DR|SR1|1| IMM5 | */
+        let dr = (instr >> 9) & 0x7;
+        let sr1 = (instr >> 6) & 0x7;
+        let imm_flag = (instr >> 5) & 1 != 0;
+
+        let value = if imm_flag {
+            sign_extend(instr & 0x1F, 5)
+        } else {
+            let sr2 = instr & 0x7;
+            self.registers.get(sr2)
+        };
+        self.registers.set(dr, self.registers.get(sr1).wrapping_add(value));
+        STATUS::Continue
+    }
+
+    fn op_and(&mut self, instr: u16) -> STATUS {
+        /* |0001| DR|SR1|0|00|SR2|
+           |0001| DR|SR1|1| IMM5 | */
+        let dr = (instr >> 9) & 0x7;
+        let sr1 = (instr >> 6) & 0x7;
+        let imm_flag = (instr >> 5) & 1 != 0;
+
+        let value = if imm_flag {
+            sign_extend(instr & 0x1F, 5)
+        } else {
+            let sr2 = instr & 0x7;
+            self.registers.get(sr2)
+        };
+        self.registers.set(dr, self.registers.get(sr1) & value);
+        STATUS::Continue
+    }
+
+    fn op_not(&mut self, instr: u16) -> STATUS {
+        /* |1001| DR| SR|111111| */
+        let dr = (instr >> 9) & 0x7;
+        let sr = (instr >> 6) & 0x7;
+        self.registers.set(dr, !self.registers.get(sr));
+        STATUS::Continue
+    }
+
+    fn op_br(&mut self, instr: u16) -> STATUS {
+        /* |0000|N|Z|P|PCoffset9| */
+        let pc_offset = sign_extend(instr & 0x1FF, 9);
+        let cond_flag = (instr >> 9) & 0x7;
+        if (cond_flag & self.registers.condition()) != 0 {
+            self.registers.program_count =
+                self.registers.program_count.wrapping_add(pc_offset);
+        }
+        STATUS::Continue
+    }
+
+    fn op_jmp(&mut self, instr: u16) -> STATUS {
+        /* |1100|000| SR|000000| (RET when SR=7) */
+        let sr = (instr >> 6) & 0x7;
+        let target = self.registers.get(sr);
+        if self.is_wild_jump(target) {
+            return STATUS::WildJump { target };
+        }
+        if sr == 7 {
+            self.check_return(target);
+        }
+        self.registers.program_count = target;
+        STATUS::Continue
+    }
+
+    fn op_jsr(&mut self, instr: u16) -> STATUS {
+        /*  JSR: |0100|1|  PCoffset11 | */
+        /* JSRR: |0100|0|00| SR|000000| */
+        let long_flag = (instr >> 11) & 1 != 0;
+        if !long_flag {
+            let target = self.registers.get((instr >> 6) & 0x7);
+            if self.is_wild_jump(target) {
+                return STATUS::WildJump { target };
+            }
+        }
+        self.registers.regs[7] = self.registers.program_count;
+        if let Some(calls) = &mut self.call_stack {
+            calls.push(self.registers.regs[7]);
+        }
+        if long_flag {
+            let long_pc_offset = sign_extend(instr & 0x7FF, 11);
+            self.registers.program_count =
+                self.registers.program_count.wrapping_add(long_pc_offset);
+        } else {
+            let sr = (instr >> 6) & 0x7;
+            self.registers.program_count = self.registers.get(sr);
+        }
+        STATUS::Continue
+    }
+
+    fn op_ld(&mut self, instr: u16) -> STATUS {
+        /* |0010| DR|PCoffset9| */
+        let dr = (instr >> 9) & 0x7;
+        let pc_offset = sign_extend(instr & 0x1FF, 9);
+        let address = self.registers.program_count.wrapping_add(pc_offset);
+        let value = self.load(address);
+        self.registers.set(dr, value);
+        STATUS::Continue
+    }
+
+    fn op_ldi(&mut self, instr: u16) -> STATUS {
+        /* |1010| DR|PCoffset9| */
+        let dr = (instr >> 9) & 0x7;
+        let pc_offset = sign_extend(instr & 0x1FF, 9);
+        /* add pc_offset to the current PC, look at that memory location to get the final address */
+        let address = self.load_pointer(self.registers.program_count.wrapping_add(pc_offset));
+        /* the final load goes through `load`, so a pointer to a device
+           register (e.g. KBSR) polls the device just like LD would */
+        let value = self.load(address);
+        self.registers.set(dr, value);
+        STATUS::Continue
+    }
+
+    fn op_ldr(&mut self, instr: u16) -> STATUS {
+        /* |0110| DR| SR|offset6| */
+        let dr = (instr >> 9) & 0x7;
+        let sr = (instr >> 6) & 0x7;
+        let offset = sign_extend(instr & 0x3F, 6);
+        let address = self.registers.get(sr).wrapping_add(offset);
+        let value = self.load(address);
+        self.registers.set(dr, value);
+        STATUS::Continue
+    }
+
+    fn op_lea(&mut self, instr: u16) -> STATUS {
+        /* |1110| DR|PCoffset9| */
+        let dr = (instr >> 9) & 0x7;
+        let pc_offset = sign_extend(instr & 0x1FF, 9);
+        self.registers.set(dr, self.registers.program_count.wrapping_add(pc_offset));
+        STATUS::Continue
+    }
+
+    fn op_st(&mut self, instr: u16) -> STATUS {
+        /* |0011| SR|PCoffset9| */
+        let sr = (instr >> 9) & 0x7;
+        let pc_offset = sign_extend(instr & 0x1FF, 9);
+        let address = self.registers.program_count.wrapping_add(pc_offset);
+        if self.is_address_wrap(self.registers.program_count, pc_offset) {
+            return STATUS::AddressWrap { address };
+        }
+        if self.is_trap_table_write(address) {
+            return STATUS::TrapTableWrite { addr: address };
+        }
+        self.store(address, self.registers.get(sr));
+        STATUS::Continue
+    }
+
+    fn op_sti(&mut self, instr: u16) -> STATUS {
+        /* |1011| SR|PCoffset9| */
+        let sr = (instr >> 9) & 0x7;
+        let pc_offset = sign_extend(instr & 0x1FF, 9);
+        let address = self.load_pointer(self.registers.program_count.wrapping_add(pc_offset));
+        /* the pointer is a plain load and the final store goes
+           through `store`, the same as ST */
+        if self.is_trap_table_write(address) {
+            return STATUS::TrapTableWrite { addr: address };
+        }
+        self.store(address, self.registers.get(sr));
+        STATUS::Continue
+    }
+
+    fn op_str(&mut self, instr: u16) -> STATUS {
+        /* |0111| SR| DR|offset6| */
+        let sr = (instr >> 9) & 0x7;
+        let dr = (instr >> 6) & 0x7;
+        let offset = sign_extend(instr & 0x3F, 6);
+        let address = self.registers.get(dr).wrapping_add(offset);
+        if self.is_address_wrap(self.registers.get(dr), offset) {
+            return STATUS::AddressWrap { address };
+        }
+        if self.is_trap_table_write(address) {
+            return STATUS::TrapTableWrite { addr: address };
+        }
+        self.store(address, self.registers.get(sr));
+        STATUS::Continue
+    }
+
+    fn op_trap(&mut self, instr: u16) -> STATUS {
+        if self.use_os_traps {
+            /* |1111|0000|trapvec8| */
+            self.registers.regs[7] = self.registers.program_count;
+            if let Some(calls) = &mut self.call_stack {
+                calls.push(self.registers.regs[7]);
+            }
+            self.registers.program_count = self.memory.read(instr & 0xFF);
+            return STATUS::Continue;
+        }
+        /* |1111|0000|trapvec8| */
+        if !self.trap_preserves_r7 {
+            self.registers.regs[7] = self.registers.program_count;
+            if let Some(calls) = &mut self.call_stack {
+                calls.push(self.registers.regs[7]);
+            }
+        }
+        match TRAP::from_u16(instr & 0xFF) {
+            Some(TRAP::GETC) => {
+                let c = self.memory.get_char();
+                if c == 0 && self.eof_halt && self.memory.io().at_eof() {
+                    self.diagnostic("HALT: end of input");
+                    self.stop_clock();
+                    return STATUS::Halted;
+                } else if c == 0 {
+                    // If we get a null character, we roll back the
+                    // instruction and suspend program execution to
+                    // await user input.
+                    self.registers.program_count = self.registers.program_count.wrapping_sub(1);
+                    return STATUS::HardInterrupt;
+                } else {
+                    self.registers.set(0, c as u16);
+                }
+            }
+            Some(TRAP::OUT) => {
+                self.memory.io().put_char(self.registers.regs[0] as u8);
+            }
+            Some(TRAP::PUTS) => {
+                let mut c = self.registers.regs[0];
+                loop {
+                    let word = self.memory.read(c);
+                    if word == 0 {
+                        break;
+                    }
+                    self.memory.io().put_char(word as u8);
+                    c = c.wrapping_add(1);
+                }
+            }
+            Some(TRAP::IN) => {
+                /* prompt once, not again each time the IN is retried while
+                   waiting for input */
+                if !self.in_prompted {
+                    b"Enter a character: \n".iter().for_each(|&c| self.memory.io().put_char(c));
+                    self.in_prompted = true;
+                }
+
+                let c = self.memory.get_char();
+                if c == 0 && self.eof_halt && self.memory.io().at_eof() {
+                    self.diagnostic("HALT: end of input");
+                    self.stop_clock();
+                    return STATUS::Halted;
+                } else if c == 0 {
+                    // If we get a null character, we roll back the
+                    // instruction and suspend program execution to
+                    // await user input.
+                    self.registers.program_count = self.registers.program_count.wrapping_sub(1);
+                    return STATUS::HardInterrupt;
+                } else {
+                    self.in_prompted = false;
+                    self.memory.io().put_char(c);
+                    self.registers.set(0, c as u16);
+                }
+            }
+            Some(TRAP::PUTSP) => {
+                /* one char per byte (two bytes per word)
+                   here we need to swap back to
+                   big endian format */
+                let mut c = self.registers.regs[0];
+                loop {
+                    let word = self.memory.read(c);
+                    if word == 0 {
+                        break;
+                    }
+                    let c1 = word & 0xFF;
+                    self.memory.io().put_char(c1 as u8);
+                    let c2 = word >> 8;
+                    if c2 != 0 { self.memory.io().put_char(c2 as u8); };
+                    c = c.wrapping_add(1);
+                }
+            }
+            Some(TRAP::HALT) => {
+                self.diagnostic("HALT");
+                self.stop_clock();
+                return STATUS::Halted;
+            }
+            Some(TRAP::CLOCK) => {
+                /* not part of the LC-3 spec: counts every instruction
+                   before this trap */
+                let count = self.instructions_executed;
+                self.registers.regs[0] = count as u16;
+                self.registers.regs[1] = (count >> 16) as u16;
+            }
+            Some(TRAP::RAND) => {
+                /* not part of the LC-3 spec */
+                let word = self.next_random();
+                self.registers.regs[0] = word;
+            }
+            None => {
+                return self.illegal(ExecError::UnknownTrap(instr as u8));
+            }
+        }
+        STATUS::Continue
+    }
+
+    #[cfg_attr(not(feature = "extensions"), allow(unused_variables))]
+    fn op_res(&mut self, instr: u16) -> STATUS {
+        #[cfg(feature = "extensions")]
+        let handled = match &mut self.reserved_handler {
+            Some(handler) => {
+                handler(instr, &mut self.registers, &mut self.memory);
+                true
+            }
+            None => false,
+        };
+        #[cfg(not(feature = "extensions"))]
+        let handled = false;
+
+        if !handled {
+            return self.illegal(ExecError::ReservedOp);
+        }
+        STATUS::Continue
+    }
+
+    fn op_rti(&mut self, _instr: u16) -> STATUS {
+        /* |1000|000000000000| */
+        if self.registers.privilege() {
+            self.return_from_interrupt();
+        } else {
+            /* without an operating system to handle the exception,
+               treat it as an invalid operation */
+            let entry = interrupt::INTERRUPT_VECTOR_TABLE + interrupt::PRIVILEGE_EXCEPTION as u16;
+            if self.memory.peek(entry) == 0 {
+                return self.illegal(ExecError::PrivilegeViolation);
+            }
+            self.exception(interrupt::PRIVILEGE_EXCEPTION);
+        }
+        STATUS::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{capture_diagnostics, capture_io, load};
+    use crate::{ExecError, STATUS};
+
+    #[test]
+    fn store_to_the_next_instruction_executes_the_new_word() {
+        let mut vm = load(
+            ".ORIG x3000
+             LD R1, PATCH
+             ST R1, NEXT
+             NEXT ADD R0, R0, #1
+             HALT
+             PATCH ADD R0, R0, #7
+             .END",
+        );
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(0), 7);
+    }
+
+    #[test]
+    fn trap_saves_r7_unless_told_to_preserve_it() {
+        let source = ".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"hi\"\n.END";
+        let mut vm = load(source);
+        vm.set_register(7, 0x1234);
+        vm.step();
+        vm.step();
+        assert_eq!(vm.register(7), 0x3002);
+
+        let mut vm = load(source);
+        vm.set_trap_preserves_r7(true);
+        vm.set_register(7, 0x1234);
+        vm.step();
+        vm.step();
+        assert_eq!(vm.register(7), 0x1234);
+    }
+
+    #[cfg(feature = "extensions")]
+    #[test]
+    fn reserved_handler_implements_multiply() {
+        /* MUL DR, SR1, SR2 encoded as |1101|DR|SR1|000|SR2| */
+        let mut vm = load(".ORIG x3000\nMUL .FILL xD240\nHALT\n.END");
+        vm.set_reserved_handler(Box::new(|instr, registers, _memory| {
+            let product = registers.get(instr >> 6).wrapping_mul(registers.get(instr));
+            registers.set(instr >> 9, product);
+        }));
+        vm.set_register(0, 6);
+        vm.set_register(1, 7);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(1), 42);
+    }
+
+    #[test]
+    fn ldi_through_a_pointer_to_kbsr_polls_the_keyboard() {
+        let mut vm = load(".ORIG x3000\nLDI R0, PTR\nHALT\nPTR .FILL xFE00\n.END");
+        vm.push_input(b"k");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(0), 0x8000);
+        assert_eq!(vm.keyboard_polls(), 1);
+    }
+
+    #[test]
+    fn ldi_pointer_fetch_is_a_plain_load() {
+        /* the pointer itself is the KBSR word, which must not be polled */
+        let mut vm = load(".ORIG xFDFF\nLDI R0, #0\n.END");
+        vm.poke(0xFE00, 0x3100);
+        vm.poke(0x3100, 0x1234);
+        vm.push_input(b"k");
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!(vm.register(0), 0x1234);
+        assert_eq!(vm.keyboard_polls(), 0);
+    }
+
+    #[test]
+    fn clock_trap_counts_the_instructions_before_it() {
+        let mut vm = load(
+            ".ORIG x3000\nTRAP x26\nADD R2, R0, #0\nAND R3, R3, #0\nADD R3, R3, #3\n\
+             LOOP ADD R3, R3, #-1\nBRp LOOP\nTRAP x26\nHALT\n.END",
+        );
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(2), 0);
+        assert_eq!(vm.register(0), 10);
+        assert_eq!(vm.register(1), 0);
+    }
+
+    #[test]
+    fn str_past_the_top_of_memory_wraps_unless_guarded() {
+        let source = ".ORIG x3000\nSTR R0, R1, #2\n.END";
+        for guard in [false, true] {
+            let mut vm = load(source);
+            vm.set_register(0, 7);
+            vm.set_register(1, 0xFFFF);
+            vm.set_address_wrap_guard(guard);
+            if guard {
+                assert_eq!(vm.step(), STATUS::AddressWrap { address: 0x0001 });
+                assert_eq!(vm.peek(0x0001), 0);
+            } else {
+                assert_eq!(vm.step(), STATUS::Continue);
+                assert_eq!(vm.peek(0x0001), 7);
+            }
+        }
+    }
+
+    #[test]
+    fn trap_table_guard_stops_user_stores_to_the_table() {
+        let source = ".ORIG x3000\nLD R1, VECTOR\nSTR R0, R1, #0\nHALT\nVECTOR .FILL x0020\n.END";
+        let mut vm = load(source);
+        vm.set_register(0, 0x4000);
+        vm.set_trap_table_guard(true);
+        vm.step();
+        assert_eq!(vm.step(), STATUS::TrapTableWrite { addr: 0x0020 });
+        assert_eq!(vm.peek(0x0020), 0);
+
+        let mut vm = load(source);
+        vm.set_register(0, 0x4000);
+        vm.step();
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!(vm.peek(0x0020), 0x4000);
+    }
+
+    #[test]
+    fn pc_wraps_at_both_ends_of_memory() {
+        let mut vm = load(".ORIG x3000\nHALT\n.END");
+        vm.set_pc(0xFFFF);
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!(vm.pc(), 0x0000);
+
+        let mut vm = load(".ORIG x3000\nHALT\n.END");
+        vm.poke(0x0000, 0xF020); // GETC
+        vm.set_pc(0x0000);
+        assert_eq!(vm.step(), STATUS::HardInterrupt);
+        assert_eq!(vm.pc(), 0x0000);
+    }
+
+    #[test]
+    fn rti_pops_a_fake_frame() {
+        let mut vm = load(".ORIG x3000\nRTI\n.END");
+        vm.poke(0xFFFC, 0x0002);
+        vm.set_register(6, 0x2FFE);
+        vm.poke(0x2FFE, 0x4000);
+        vm.poke(0x2FFF, 0x0001);
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!((vm.pc(), vm.peek(0xFFFC), vm.register(6)), (0x4000, 0x0001, 0x3000));
+
+        /* returning to user mode switches back to the user stack */
+        let mut vm = load(".ORIG x3000\nRTI\n.END");
+        vm.poke(0xFFFC, 0x0002);
+        vm.registers.saved_usp = 0xFE00;
+        vm.set_register(6, 0x2FFE);
+        vm.poke(0x2FFE, 0x4000);
+        vm.poke(0x2FFF, 0x8001);
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!((vm.pc(), vm.peek(0xFFFC), vm.register(6)), (0x4000, 0x8001, 0xFE00));
+        assert_eq!(vm.registers.saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn rti_in_user_mode_is_a_privilege_violation() {
+        let mut vm = load(".ORIG x3000\nRTI\n.END");
+        assert_eq!(vm.step(), STATUS::Fault(ExecError::PrivilegeViolation));
+    }
+
+    #[test]
+    fn invalid_operations_return_a_fault() {
+        let mut vm = load(".ORIG x3000\n.FILL xD000\n.END");
+        assert_eq!(vm.step(), STATUS::Fault(ExecError::ReservedOp));
+
+        let mut vm = load(".ORIG x3000\nTRAP x30\n.END");
+        assert_eq!(vm.step(), STATUS::Fault(ExecError::UnknownTrap(0x30)));
+    }
+
+    #[test]
+    fn getc_loop_echoes_scripted_input() {
+        let mut vm = load(".ORIG x3000\nGETC\nOUT\nGETC\nOUT\nHALT\n.END");
+        let io = capture_io(&mut vm);
+        io.borrow_mut().input.extend(b"hi");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(io.borrow().output, b"hi");
+    }
+
+    #[test]
+    fn in_prompts_through_the_io_device_once() {
+        let mut vm = load(".ORIG x3000\nIN\nHALT\n.END");
+        let result = vm.run_and_capture(b"a", 100);
+        assert_eq!((result.status, result.output), (STATUS::Halted, b"Enter a character: \na".to_vec()));
+
+        let mut vm = load(".ORIG x3000\nIN\nIN\nHALT\n.END");
+        let result = vm.run_and_capture(b"", 100);
+        assert_eq!(result.status, STATUS::HardInterrupt);
+        let result = vm.run_and_capture(b"ab", 100);
+        assert_eq!(result.status, STATUS::Halted);
+        assert_eq!(result.output, b"aEnter a character: \nb");
+    }
+
+    #[test]
+    fn mixed_program_ends_in_the_known_state() {
+        let mut vm = load(
+            "
+            .ORIG x3000
+            LD R1, ONE
+            LDI R2, PTR
+            LEA R3, DATA
+            LDR R4, R3, #0
+            ADD R5, R2, R4
+            AND R6, R5, #6
+            ADD R1, R1, #-1
+            BRz SKIP
+            ADD R0, R0, #15
+    SKIP    JSR SUB
+            LEA R1, SUB
+            JSRR R1
+            NOT R6, R6
+            ST R5, RESULT
+            STI R6, PTR
+            STR R0, R3, #1
+            LEA R1, DONE
+            JMP R1
+            ADD R0, R0, #1
+    DONE    HALT
+    SUB     ADD R0, R0, #2
+            RET
+    ONE     .FILL 1
+    PTR     .FILL DATA
+    DATA    .FILL 7
+            .FILL 0
+    RESULT  .FILL 0
+            .END
+            ",
+        );
+        assert_eq!(vm.run(), STATUS::Halted);
+        /* R7 holds the return address of the HALT trap */
+        assert_eq!(vm.registers.regs, [4, 0x3013, 7, 0x3018, 7, 14, 0xFFF9, 0x3014]);
+        assert_eq!(vm.pc(), 0x3014);
+        assert_eq!(vm.peek(0x3018), 0xFFF9);
+        assert_eq!(vm.peek(0x3019), 4);
+        assert_eq!(vm.peek(0x301A), 14);
+    }
+
+    #[test]
+    fn os_traps_jump_to_a_stub_routine() {
+        let source = ".ORIG x3000\nOUT\nADD R2, R2, #1\nHALT\nSTUB ADD R1, R1, #5\nRET\n.END";
+        let mut vm = load(source);
+        vm.set_use_os_traps(true);
+        vm.poke(0x0021, 0x3003);
+
+        assert_eq!(vm.step(), STATUS::Continue);
+        assert_eq!((vm.pc(), vm.register(7)), (0x3003, 0x3001));
+        vm.step();
+        vm.step();
+        assert_eq!(vm.pc(), 0x3001);
+        assert_eq!(vm.register(1), 5);
+
+        /* natively, OUT never reaches the stub */
+        let mut vm = load(source);
+        vm.poke(0x0021, 0x3003);
+        vm.step();
+        assert_eq!(vm.pc(), 0x3001);
+        assert_eq!(vm.register(1), 0);
+    }
+
+    #[test]
+    fn ret_from_an_os_trap_is_a_valid_return() {
+        let mut vm = load(".ORIG x3000\nOUT\nHALT\nSTUB RET\n.END");
+        let messages = capture_diagnostics(&mut vm);
+        vm.set_return_guard(true);
+        vm.set_use_os_traps(true);
+        vm.poke(0x0021, 0x3002);
+
+        vm.step();
+        assert_eq!(vm.call_stack(), Some(&[0x3001][..]));
+        vm.step();
+        assert_eq!(vm.pc(), 0x3001);
+        assert_eq!(vm.call_stack(), Some(&[][..]));
+        assert!(messages.borrow().is_empty());
+    }
+}
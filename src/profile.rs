@@ -0,0 +1,64 @@
+//! Per-address execution counts for finding hotspots.
+
+use std::io::Write;
+
+use crate::disasm::disassemble;
+use crate::VM;
+
+impl VM {
+    /// Count how many times each address is executed. Turning profiling
+    /// on or off clears the counts.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+        self.profile.clear();
+    }
+
+    /// How many times the instruction at `addr` was executed while
+    /// profiling.
+    pub fn execution_count(&self, addr: u16) -> u64 {
+        self.profile.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn record_execution(&mut self, pc: u16) {
+        *self.profile.entry(pc).or_default() += 1;
+    }
+
+    /// Write the profile as CSV with one row per executed address: the
+    /// address, the instruction word currently there, its disassembly and
+    /// the execution count. Rows are sorted by count, highest first.
+    pub fn export_profile_csv(&self, mut writer: impl Write) -> std::io::Result<()> {
+        let mut rows: Vec<(u16, u64)> = self.profile.iter().map(|(&addr, &count)| (addr, count)).collect();
+        rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        writeln!(writer, "address,instruction,disassembly,count")?;
+        for (addr, count) in rows {
+            let word = self.memory.peek(addr);
+            writeln!(writer, "x{:04X},x{:04X},\"{}\",{}", addr, word, disassemble(word), count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::load;
+    use crate::STATUS;
+
+    #[test]
+    fn csv_puts_the_loop_body_first() {
+        let mut vm = load(
+            ".ORIG x3000\nAND R1, R1, #0\nADD R1, R1, #5\n\
+             LOOP ADD R0, R0, R1\nADD R1, R1, #-1\nBRp LOOP\nHALT\n.END",
+        );
+        vm.set_profiling(true);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.execution_count(0x3002), 5);
+
+        let mut csv = Vec::new();
+        vm.export_profile_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("address,instruction,disassembly,count"));
+        assert_eq!(lines.next(), Some("x3002,x1001,\"ADD R0, R0, R1\",5"));
+    }
+}
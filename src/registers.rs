@@ -1,29 +1,32 @@
-use num_traits::FromPrimitive;
-
-use crate::OP;
 use crate::memory::Memory;
 
+#[derive(Clone)]
 pub struct Registers {
-    pub r0: u16,
-    pub r1: u16,
-    pub r2: u16,
-    pub r3: u16,
-    pub r4: u16,
-    pub r5: u16,
-    pub r6: u16,
-    pub r7: u16,
+    /// The general purpose registers R0-R7.
+    pub regs: [u16; 8],
     pub program_count: u16,
-    pub condition: u16,
+    /// The Processor Status Register: the privilege mode (bit 15, set in
+    /// user mode), the priority level (bits 10-8) and the N/Z/P condition
+    /// codes (bits 2-0).
+    pub psr: u16,
+    /// The stack pointer of whichever of the supervisor and user stacks
+    /// isn't in R6.
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
 }
 
 impl Default for Registers {
     fn default() -> Self {
         Self {
-            r0: 0, r1: 0, r2: 0, r3: 0, r4: 0, r5: 0, r6: 0, r7: 0,
+            regs: [0; 8],
             /* set the PC to starting position - 0x3000 is the default */
             program_count: 0x3000,
-            /* since exactly one condition flag should be set at any given time, set the Z flag */
-            condition: 0b010,
+            /* start in user mode at priority 0, and since exactly one condition
+               flag should be set at any given time, set the Z flag */
+            psr: 0x8000 | 0b010,
+            /* the supervisor stack conventionally grows down from 0x3000 */
+            saved_ssp: 0x3000,
+            saved_usp: 0,
         }
     }
 }
@@ -37,48 +40,92 @@ impl Default for Registers {
 // }
 
 impl Registers {
-    #[allow(unreachable_code)]
     pub fn get(&self, r: u16) -> u16 {
-        match r & 0x7 {
-            0 => self.r0,
-            1 => self.r1,
-            2 => self.r2,
-            3 => self.r3,
-            4 => self.r4,
-            5 => self.r5,
-            6 => self.r6,
-            7 => self.r7,
-            _ => !unreachable!(),
-        }
+        self.regs[(r & 0x7) as usize]
     }
 
-    #[allow(unreachable_code)]
     pub fn set(&mut self, r: u16, value: u16) {
-        match r & 0x7 {
-            0 => self.r0 = value,
-            1 => self.r1 = value,
-            2 => self.r2 = value,
-            3 => self.r3 = value,
-            4 => self.r4 = value,
-            5 => self.r5 = value,
-            6 => self.r6 = value,
-            7 => self.r7 = value,
-            _ => !unreachable!(),
-        }
+        self.regs[(r & 0x7) as usize] = value;
 
         // Set the condition flag.
-        self.condition = match value {
+        self.set_condition(match value {
             0        => 0b010,
             0x8000.. => 0b100,
             _        => 0b001,
-        };
+        });
+    }
+
+    /// The N/Z/P condition codes, as the low three bits.
+    pub fn condition(&self) -> u16 {
+        self.psr & 0x7
+    }
+
+    pub fn set_condition(&mut self, nzp: u16) {
+        self.psr = (self.psr & !0x7) | (nzp & 0x7);
+    }
+
+    /// Whether the processor is in (privileged) supervisor mode.
+    pub fn privilege(&self) -> bool {
+        self.psr & 0x8000 == 0
     }
 
-    pub fn next(&mut self, memory: &mut Memory) -> (u16, Option<OP>) {
+    /// The priority level, 0-7.
+    pub fn priority(&self) -> u16 {
+        (self.psr >> 8) & 0x7
+    }
+
+    /// Fetch the instruction at the PC and advance the PC.
+    /// Instructions are always fetched from memory (there is no decode
+    /// cache), so a store to the word at the PC is picked up by the very
+    /// next fetch. This keeps self-modifying programs working.
+    pub(crate) fn fetch(&mut self, memory: &mut Memory) -> u16 {
         let pc = self.program_count;
-        self.program_count += 1;
-        let instruction = memory.read(pc);
-        let operation = OP::from_u16(instruction >> 12);
-        (instruction, operation)
+        self.program_count = self.program_count.wrapping_add(1);
+        memory.read(pc)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load;
+    use crate::STATUS;
+
+    #[test]
+    fn condition_codes_live_in_the_psr() {
+        let mut registers = Registers::default();
+        assert_eq!(registers.psr, 0x8002);
+        assert!(!registers.privilege());
+        registers.set(0, 0xFFFB);
+        assert_eq!(registers.psr, 0x8004);
+        assert_eq!(registers.condition(), 0b100);
+
+        registers.psr = 0x0401;
+        assert!(registers.privilege());
+        assert_eq!(registers.priority(), 4);
+    }
+
+    #[test]
+    fn loading_a_negative_value_sets_n() {
+        let mut vm = load(".ORIG x3000\nLD R0, NEG\nHALT\nNEG .FILL #-5\n.END");
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.registers.psr & 0x7, 0b100);
+        assert_eq!(vm.peek(0xFFFC), 0x8004);
+    }
+
+    #[test]
+    fn registers_are_independent_and_masked() {
+        let mut registers = Registers::default();
+        for r in 0..8 {
+            registers.set(r, 0x1000 + r);
+        }
+        for r in 0..8 {
+            assert_eq!(registers.get(r), 0x1000 + r);
+        }
+        /* only the low three bits pick the register */
+        assert_eq!(registers.get(9), 0x1001);
+        registers.set(0xFFFF, 0x8000);
+        assert_eq!(registers.get(7), 0x8000);
+        assert_eq!(registers.regs[..7], [0x1000, 0x1001, 0x1002, 0x1003, 0x1004, 0x1005, 0x1006]);
+    }
+}
@@ -0,0 +1,225 @@
+//! Running untrusted images with bounded execution and captured I/O.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::io::Io;
+use crate::{Diagnostics, STATUS, VM};
+
+/// Resource limits for `VM::run_sandboxed`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// The maximum number of instructions to execute.
+    pub max_steps: u64,
+    /// The maximum number of output bytes to capture.
+    pub max_output: usize,
+    /// Disable the memory-mapped keyboard so that input only comes from
+    /// the GETC/IN traps.
+    pub no_device_io: bool,
+}
+
+/// The step limit for each run made by `equivalent`.
+const EQUIVALENCE_STEPS: u64 = 1_000_000;
+
+/// The outcome of a bounded run. `output` holds whatever the program wrote
+/// before it stopped, however it stopped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunResult {
+    /// Why the run ended:
+    /// - `Halted` for a HALT trap,
+    /// - `Fault` for an invalid operation,
+    /// - `HardInterrupt` if the program wanted more input than was given,
+    /// - `StepLimitReached`/`OutputLimitReached` if a limit was hit,
+    /// - or the status raised by one of the guards.
+    pub status: STATUS,
+    pub output: Vec<u8>,
+    pub steps: u64,
+    /// The general purpose registers when the run ended.
+    pub registers: [u16; 8],
+}
+
+/// Feeds a fixed input and captures output, keeping one byte more than
+/// the limit so that overflowing it can be detected.
+struct SandboxIo {
+    input: VecDeque<u8>,
+    output: Rc<RefCell<Vec<u8>>>,
+    max_output: usize,
+}
+
+impl Io for SandboxIo {
+    fn get_char(&mut self) -> u8 {
+        self.input.pop_front().unwrap_or(0)
+    }
+
+    fn put_char(&mut self, c: u8) {
+        let mut output = self.output.borrow_mut();
+        if output.len() <= self.max_output {
+            output.push(c);
+        }
+    }
+}
+
+impl VM {
+    /// Run the loaded program with `input` as its only input, capturing its
+    /// output, until it halts, runs out of input, or exceeds a limit. The
+    /// VM's own I/O device is restored afterwards.
+    pub fn run_sandboxed(&mut self, input: &[u8], limits: Limits) -> RunResult {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let io = SandboxIo {
+            input: input.iter().copied().collect(),
+            output: output.clone(),
+            max_output: limits.max_output,
+        };
+        let previous_io = self.memory.set_io(Box::new(io));
+        let keyboard_enabled = self.memory.keyboard_enabled();
+        if limits.no_device_io {
+            self.memory.set_keyboard_enabled(false);
+        }
+
+        let mut steps = 0;
+        let status = loop {
+            if steps >= limits.max_steps {
+                break STATUS::StepLimitReached;
+            }
+            let (status, executed) = self.run_slice(1);
+            steps += executed;
+            if output.borrow().len() > limits.max_output {
+                break STATUS::OutputLimitReached;
+            }
+            match status {
+                STATUS::Continue | STATUS::SoftInterrupt => {}
+                status => break status,
+            }
+        };
+
+        self.memory.set_io(previous_io);
+        self.memory.set_keyboard_enabled(keyboard_enabled);
+        let mut output = output.take();
+        output.truncate(limits.max_output);
+        RunResult { status, output, steps, registers: self.registers.regs }
+    }
+
+    /// Run the loaded program with `input` as its only input, handing each
+    /// output byte to `on_output` as it is produced instead of capturing
+    /// it all, until it halts or runs out of input. There is no step
+    /// limit. Returns the status it stopped with, as for `RunResult`.
+    pub fn run_streaming(&mut self, input: &[u8], mut on_output: impl FnMut(u8)) -> STATUS {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let io = SandboxIo {
+            input: input.iter().copied().collect(),
+            output: output.clone(),
+            max_output: usize::MAX,
+        };
+        let previous_io = self.memory.set_io(Box::new(io));
+
+        let status = loop {
+            let (status, _) = self.run_slice(1);
+            output.borrow_mut().drain(..).for_each(&mut on_output);
+            match status {
+                STATUS::Continue | STATUS::SoftInterrupt => {}
+                status => break status,
+            }
+        };
+
+        self.memory.set_io(previous_io);
+        status
+    }
+
+    /// Run with `input` for at most `max_steps` instructions, capturing all
+    /// of the output. See `RunResult` for how the run can end.
+    pub fn run_and_capture(&mut self, input: &[u8], max_steps: u64) -> RunResult {
+        let limits = Limits { max_steps, max_output: usize::MAX, no_device_io: false };
+        self.run_sandboxed(input, limits)
+    }
+}
+
+/// Whether two object images behave the same: run headless against each
+/// input set, both must produce the same output and stop with the same
+/// status. Each run is limited to a million instructions. An image that
+/// fails to load is never equivalent to anything.
+pub fn equivalent(img_a: &[u8], img_b: &[u8], inputs: &[&[u8]]) -> bool {
+    let run = |image: &[u8], input: &[u8]| {
+        let mut vm = VM::default();
+        vm.set_diagnostics(Diagnostics::Silent);
+        vm.load_bytes(image).ok()?;
+        Some(vm.run_and_capture(input, EQUIVALENCE_STEPS))
+    };
+    inputs.iter().all(|input| match (run(img_a, input), run(img_b, input)) {
+        (Some(a), Some(b)) => a.status == b.status && a.output == b.output,
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load;
+    use crate::ExecError;
+
+    #[test]
+    fn limits_stop_an_infinite_program() {
+        let limits = Limits { max_steps: 1000, max_output: 100, no_device_io: true };
+        let mut vm = load(".ORIG x3000\nLOOP BR LOOP\n.END");
+        let result = vm.run_sandboxed(b"", limits);
+        assert_eq!(result.status, STATUS::StepLimitReached);
+        assert_eq!(result.steps, 1000);
+
+        let mut vm = load(".ORIG x3000\nLD R0, CHAR\nLOOP OUT\nBR LOOP\nCHAR .FILL x61\n.END");
+        let result = vm.run_sandboxed(b"", limits);
+        assert_eq!(result.status, STATUS::OutputLimitReached);
+        assert_eq!(result.output, [b'a'; 100]);
+    }
+
+    #[test]
+    fn each_way_of_stopping_keeps_the_output_so_far() {
+        let mut vm = load(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"done\"\n.END");
+        let result = vm.run_and_capture(b"", 100);
+        assert_eq!((result.status, result.output), (STATUS::Halted, b"done".to_vec()));
+
+        let mut vm = load(".ORIG x3000\nLD R0, CHAR\nLOOP OUT\nBR LOOP\nCHAR .FILL x61\n.END");
+        let result = vm.run_and_capture(b"", 5);
+        assert_eq!((result.status, result.output), (STATUS::StepLimitReached, b"aa".to_vec()));
+
+        let mut vm = load(".ORIG x3000\nLOOP GETC\nOUT\nBR LOOP\n.END");
+        let result = vm.run_and_capture(b"ab", 100);
+        assert_eq!((result.status, result.output), (STATUS::HardInterrupt, b"ab".to_vec()));
+
+        let mut vm = load(".ORIG x3000\nLD R0, CHAR\nOUT\n.FILL xD000\nCHAR .FILL x61\n.END");
+        let result = vm.run_and_capture(b"", 100);
+        assert_eq!(
+            (result.status, result.output),
+            (STATUS::Fault(ExecError::ReservedOp), b"a".to_vec())
+        );
+    }
+
+    #[test]
+    fn programs_printing_the_same_text_are_equivalent() {
+        let print = |text: &str| {
+            let source = format!(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"{}\"\n.END", text);
+            crate::assemble(&source).unwrap()
+        };
+        let puts = print("hi");
+        let outs = crate::assemble(
+            ".ORIG x4000\nLD R0, H\nOUT\nLD R0, I\nOUT\nHALT\nH .FILL x68\nI .FILL x69\n.END",
+        )
+        .unwrap();
+        let other = print("ho");
+        let inputs: &[&[u8]] = &[b"", b"x"];
+        assert!(equivalent(&puts, &outs, inputs));
+        assert!(!equivalent(&puts, &other, inputs));
+        assert!(!equivalent(&puts, &[0x30], inputs));
+    }
+
+    #[test]
+    fn streaming_hands_over_the_output_in_order() {
+        let mut vm = load(
+            ".ORIG x3000\nLEA R0, PROMPT\nPUTS\nLOOP GETC\nOUT\nBRnzp LOOP\nPROMPT .STRINGZ \"> \"\n.END",
+        );
+        let mut streamed = Vec::new();
+        let status = vm.run_streaming(b"abc", |c| streamed.push(c));
+        /* the echo loop waits for more input once "abc" is used up */
+        assert_eq!(status, STATUS::HardInterrupt);
+        assert_eq!(streamed, b"> abc");
+    }
+}
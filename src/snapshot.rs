@@ -0,0 +1,158 @@
+//! Saving and restoring the machine state: the registers and all of memory.
+//!
+//! The format is a 5-byte header (`LC3S` and a version byte) followed by
+//! big-endian words, like object images: R0-R7, the PC, the PSR, the saved
+//! supervisor and user stack pointers, the four words of the RAND trap's
+//! generator state, then the 65536 memory words.
+
+use std::fmt;
+
+use crate::memory::MEMORY_SIZE;
+use crate::VM;
+
+const MAGIC: &[u8; 4] = b"LC3S";
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+const REGISTER_COUNT: usize = 12;
+const RNG_WORDS: usize = 4;
+const SNAPSHOT_LEN: usize = HEADER_LEN + 2 * (REGISTER_COUNT + RNG_WORDS + MEMORY_SIZE);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// The buffer doesn't start with the snapshot header.
+    BadMagic,
+    /// The snapshot was written by an unknown version of the format.
+    UnsupportedVersion(u8),
+    /// The buffer is the wrong size for this version of the format.
+    BadLength { expected: usize, found: usize },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SnapshotError::BadMagic => write!(f, "not a VM snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::BadLength { expected, found } => {
+                write!(f, "snapshot is {} bytes, expected {}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl VM {
+    /// Serialize the registers, the RAND generator and all of memory,
+    /// including the device registers, so that `restore` can return to
+    /// this exact state.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let r = &self.registers;
+        let registers = r.regs.into_iter().chain([r.program_count, r.psr, r.saved_ssp, r.saved_usp]);
+        let mut bytes = Vec::with_capacity(SNAPSHOT_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend(registers.flat_map(|word| word.to_be_bytes()));
+        bytes.extend(self.rng_state.to_be_bytes());
+        bytes.extend((0..MEMORY_SIZE).flat_map(|addr| self.memory.peek(addr as u16).to_be_bytes()));
+        bytes
+    }
+
+    /// Return to a state saved by `snapshot`, clearing any halt so that a
+    /// restored VM runs again. The VM is left untouched if the snapshot is
+    /// invalid.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        if snapshot.len() < HEADER_LEN || &snapshot[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if snapshot[MAGIC.len()] != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(snapshot[MAGIC.len()]));
+        }
+        if snapshot.len() != SNAPSHOT_LEN {
+            return Err(SnapshotError::BadLength { expected: SNAPSHOT_LEN, found: snapshot.len() });
+        }
+
+        let mut words = snapshot[HEADER_LEN..]
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let mut next = || words.next().unwrap();
+        let r = &mut self.registers;
+        for reg in r.regs.iter_mut().chain([
+            &mut r.program_count, &mut r.psr, &mut r.saved_ssp, &mut r.saved_usp,
+        ]) {
+            *reg = next();
+        }
+        self.rng_state = (0..RNG_WORDS).fold(0, |state, _| state << 16 | next() as u64);
+        for addr in 0..MEMORY_SIZE {
+            self.memory.write(addr as u16, next());
+        }
+        self.memory.clear_last_write();
+        self.clear_halt();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load;
+    use crate::STATUS;
+
+    #[test]
+    fn restore_returns_to_the_snapshotted_registers() {
+        let mut vm = load(
+            ".ORIG x3000\nADD R0, R0, #1\nADD R1, R0, #2\nADD R0, R0, #5\nST R0, DATA\nHALT\nDATA .FILL 0\n.END",
+        );
+        vm.step();
+        vm.step();
+        let snapshot = vm.snapshot();
+        vm.step();
+        vm.step();
+        assert_eq!(vm.peek(0x3005), 6);
+
+        vm.restore(&snapshot).unwrap();
+        assert_eq!(vm.register(0), 1);
+        assert_eq!(vm.register(1), 3);
+        assert_eq!(vm.pc(), 0x3002);
+        assert_eq!(vm.peek(0x3005), 0);
+        assert_eq!(
+            vm.restore(&snapshot[..10]),
+            Err(SnapshotError::BadLength { expected: SNAPSHOT_LEN, found: 10 })
+        );
+    }
+
+    #[test]
+    fn restoring_before_a_halt_runs_again() {
+        let mut vm = load(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END");
+        let snapshot = vm.snapshot();
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert!(vm.is_halted());
+
+        vm.restore(&snapshot).unwrap();
+        assert!(!vm.is_halted());
+        assert_eq!(vm.halt_reason(), None);
+        assert_eq!(vm.run(), STATUS::Halted);
+        assert_eq!(vm.register(0), 1);
+    }
+
+    #[test]
+    fn restored_rng_repeats_its_sequence() {
+        let mut vm = load(".ORIG x3000\nTRAP x27\nTRAP x27\nBRnzp #-3\n.END");
+        vm.seed_rng(42);
+        vm.step();
+        vm.step();
+        let snapshot = vm.snapshot();
+
+        let draw = |vm: &mut VM| -> Vec<u16> {
+            (0..6).map(|_| {
+                vm.step();
+                vm.register(0)
+            }).collect()
+        };
+        let first = draw(&mut vm);
+        assert_ne!(first[0], first[1]);
+        vm.restore(&snapshot).unwrap();
+        assert_eq!(draw(&mut vm), first);
+    }
+}
@@ -0,0 +1,107 @@
+//! Symbol tables in the `.sym` format emitted by the LC-3 tools.
+//!
+//! ```text
+//! // Symbol table
+//! // Scope level 0:
+//! //  Symbol Name       Page Address
+//! //  ----------------  ------------
+//! //  START             3000
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SymbolTable(BTreeMap<String, u16>);
+
+impl SymbolTable {
+    /// Parse the contents of a `.sym` file. Header lines (and anything
+    /// else that isn't a `name address` pair) are skipped.
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::default();
+        for line in text.lines() {
+            let line = line.trim_start().trim_start_matches("//");
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(addr), None) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let addr = addr.trim_start_matches(['x', 'X']);
+            if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                table.insert(name, addr);
+            }
+        }
+        table
+    }
+
+    pub fn read(mut reader: impl Read) -> std::io::Result<SymbolTable> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        Ok(SymbolTable::parse(&text))
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.0.insert(name.to_string(), addr);
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.0.get(name).copied()
+    }
+
+    /// The first symbol (alphabetically) defined at `addr`.
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.0.iter().find(|(_, &a)| a == addr).map(|(name, _)| name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.0.iter().map(|(name, &addr)| (name.as_str(), addr))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn extend(&mut self, other: SymbolTable) {
+        self.0.extend(other.0);
+    }
+}
+
+/// Formats the table as a `.sym` file that `SymbolTable::parse` reads back.
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "// Symbol table")?;
+        writeln!(f, "// Scope level 0:")?;
+        writeln!(f, "//  Symbol Name       Page Address")?;
+        writeln!(f, "//  ----------------  ------------")?;
+        for (name, addr) in self.iter() {
+            writeln!(f, "//  {:<16}  {:04X}", name, addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_sym_file() {
+        let text = "// Symbol table\n\
+                    // Scope level 0:\n\
+                    //\tSymbol Name       Page Address\n\
+                    //\t----------------  ------------\n\
+                    //\tSTART             3000\n\
+                    //\tLOOP              3004\n\
+                    //\tMSG               x3010\n";
+        let table = SymbolTable::parse(text);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.address("START"), Some(0x3000));
+        assert_eq!(table.address("LOOP"), Some(0x3004));
+        assert_eq!(table.address("MSG"), Some(0x3010));
+        assert_eq!(table.name_at(0x3004), Some("LOOP"));
+    }
+}
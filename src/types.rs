@@ -0,0 +1,94 @@
+//! Newtypes that keep memory addresses and register indices apart in the
+//! public API. The interpreter itself works on raw `u16`s.
+
+use std::fmt;
+
+/// A memory address.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Addr(pub u16);
+
+impl From<u16> for Addr {
+    fn from(addr: u16) -> Addr {
+        Addr(addr)
+    }
+}
+
+impl From<Addr> for u16 {
+    fn from(addr: Addr) -> u16 {
+        addr.0
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x{:04X}", self.0)
+    }
+}
+
+/// One of the general purpose registers R0-R7.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Reg(u16);
+
+impl Reg {
+    pub const R0: Reg = Reg(0);
+    pub const R1: Reg = Reg(1);
+    pub const R2: Reg = Reg(2);
+    pub const R3: Reg = Reg(3);
+    pub const R4: Reg = Reg(4);
+    pub const R5: Reg = Reg(5);
+    pub const R6: Reg = Reg(6);
+    pub const R7: Reg = Reg(7);
+
+    /// The register with the given index, or `None` if it isn't 0-7.
+    pub fn new(index: u16) -> Option<Reg> {
+        (index < 8).then_some(Reg(index))
+    }
+
+    pub fn index(self) -> u16 {
+        self.0
+    }
+}
+
+impl TryFrom<u16> for Reg {
+    type Error = u16;
+
+    /// Convert a register index, returning it back if it isn't 0-7.
+    fn try_from(index: u16) -> Result<Reg, u16> {
+        Reg::new(index).ok_or(index)
+    }
+}
+
+impl From<Reg> for u16 {
+    fn from(reg: Reg) -> u16 {
+        reg.0
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "R{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_convert_both_ways() {
+        assert_eq!(Addr::from(0x3000), Addr(0x3000));
+        assert_eq!(u16::from(Addr(0xFE00)), 0xFE00);
+        assert_eq!(Addr(0x3000).to_string(), "x3000");
+    }
+
+    #[test]
+    fn registers_must_be_r0_to_r7() {
+        assert_eq!(Reg::new(7), Some(Reg::R7));
+        assert_eq!(Reg::new(8), None);
+        assert_eq!(Reg::try_from(3), Ok(Reg::R3));
+        assert_eq!(Reg::try_from(9), Err(9));
+        assert_eq!(u16::from(Reg::R5), 5);
+        assert_eq!(Reg::R2.index(), 2);
+        assert_eq!(Reg::R6.to_string(), "R6");
+    }
+}
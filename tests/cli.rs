@@ -0,0 +1,87 @@
+//! Runs the `lc3` binary the way a user would from a shell.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long `lc3` may run before a test gives up on it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `lc3` with `args`, feeding `stdin` to it. Panics if it doesn't
+/// finish within `TIMEOUT`.
+fn lc3(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lc3"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("lc3 should start");
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let start = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            panic!("lc3 {:?} didn't finish", args);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    child.wait_with_output().unwrap()
+}
+
+/// Assemble `source` into an image file named `name` in the temp directory,
+/// so that stdin is left free for the program's input.
+fn image_file(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("lc3-cli-{}-{}.obj", std::process::id(), name));
+    std::fs::write(&path, lc3::assemble(source).unwrap()).unwrap();
+    path
+}
+
+#[test]
+fn reads_the_image_from_stdin() {
+    let image = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/hello_world.obj")).unwrap();
+    let output = lc3(&["-"], &image);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Hello World!\n--HALT--\n");
+}
+
+#[test]
+fn newline_on_halt_only_when_the_output_ends_mid_line() {
+    let program = |text: &str| {
+        let source = format!(".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"{}\"\n.END", text);
+        lc3::assemble(&source).unwrap()
+    };
+    let output = lc3(&["--newline-on-halt", "-"], &program("hi"));
+    assert_eq!(output.stdout, b"hi");
+    assert_eq!(output.stderr, b"\nHALT\n");
+
+    let output = lc3(&["--newline-on-halt", "-"], &program("hi\\n"));
+    assert_eq!(output.stdout, b"hi\n");
+    assert_eq!(output.stderr, b"HALT\n");
+}
+
+#[test]
+fn eof_halt_stops_when_piped_input_runs_out() {
+    let image = image_file("echo", ".ORIG x3000\nLOOP GETC\nOUT\nBRnzp LOOP\n.END");
+    let output = lc3(&["--eof-halt", image.to_str().unwrap()], b"abc");
+    std::fs::remove_file(&image).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"abc");
+    assert_eq!(output.stderr, b"HALT: end of input\n");
+}
+
+#[test]
+fn exit_code_reports_how_the_program_stopped() {
+    let halts = image_file("halts", ".ORIG x3000\nHALT\n.END");
+    let reserved = image_file("reserved", ".ORIG x3000\n.FILL xD000\n.END");
+    let halt_status = lc3(&[halts.to_str().unwrap()], b"").status;
+    let reserved_status = lc3(&[reserved.to_str().unwrap()], b"").status;
+    std::fs::remove_file(&halts).unwrap();
+    std::fs::remove_file(&reserved).unwrap();
+
+    assert_eq!(halt_status.code(), Some(0));
+    assert_eq!(reserved_status.code(), Some(3));
+    assert_eq!(lc3(&["no-such-image.obj"], b"").status.code(), Some(1));
+    assert_eq!(lc3(&[], b"").status.code(), Some(2));
+}
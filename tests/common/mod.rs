@@ -0,0 +1,40 @@
+//! Helpers shared by the integration tests. Each test crate uses only some
+//! of them.
+#![allow(dead_code)]
+
+use lc3::{assemble, Diagnostics, RunResult, VM};
+
+/// The instruction limit for `run_source`, so a broken test program fails
+/// instead of hanging the suite.
+const MAX_STEPS: u64 = 1_000_000;
+
+/// A fresh VM with `source` assembled and loaded, warnings silenced.
+pub fn load_source(source: &str) -> VM {
+    let mut vm = VM::default();
+    vm.set_diagnostics(Diagnostics::Silent);
+    vm.load_bytes(&assemble(source).expect("test program should assemble"))
+        .expect("test program should load");
+    vm
+}
+
+/// Assemble `source`, load it into a fresh VM and run it headless with
+/// `input`, capturing its output and final registers.
+pub fn run_source(source: &str, input: &[u8]) -> RunResult {
+    load_source(source).run_and_capture(input, MAX_STEPS)
+}
+
+/// Check each `(register, value)` pair, panicking with every mismatch
+/// (expected and actual, in hex) rather than just the first.
+#[track_caller]
+pub fn assert_regs(vm: &VM, expected: &[(u16, u16)]) {
+    let mismatches: Vec<String> = expected
+        .iter()
+        .filter(|&&(reg, value)| vm.register(reg) != value)
+        .map(|&(reg, value)| {
+            format!("  R{}: expected x{:04X}, found x{:04X}", reg, value, vm.register(reg))
+        })
+        .collect();
+    if !mismatches.is_empty() {
+        panic!("registers differ:\n{}", mismatches.join("\n"));
+    }
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{assert_regs, load_source, run_source};
+use lc3::STATUS;
+
+#[test]
+fn sums_one_to_five() {
+    let result = run_source(
+        "
+        .ORIG x3000
+        AND R0, R0, #0
+        AND R1, R1, #0
+        ADD R1, R1, #5
+LOOP    ADD R0, R0, R1
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+        .END
+        ",
+        b"",
+    );
+    assert_eq!(result.status, STATUS::Halted);
+    assert_eq!(result.registers[0], 15);
+}
+
+#[test]
+fn add_sets_the_destination_register() {
+    let mut vm = load_source(
+        "
+        .ORIG x3000
+        ADD R1, R1, #5
+        ADD R2, R1, #-2
+        ADD R3, R1, R2
+        HALT
+        .END
+        ",
+    );
+    for _ in 0..3 {
+        assert_eq!(vm.step(), STATUS::Continue);
+    }
+    assert_regs(&vm, &[(1, 0x0005), (2, 0x0003), (3, 0x0008)]);
+}
+
+#[test]
+#[should_panic(expected = "R2: expected x0004, found x0003")]
+fn assert_regs_reports_the_mismatch_in_hex() {
+    let mut vm = load_source(".ORIG x3000\nADD R1, R1, #5\nADD R2, R1, #-2\n.END");
+    vm.step();
+    vm.step();
+    assert_regs(&vm, &[(1, 0x0005), (2, 0x0004)]);
+}